@@ -1,3 +1,8 @@
+use crate::torrent::{
+    decode::BinaryEncoding,
+    listener::{DEFAULT_MAX_CONNECTIONS, DEFAULT_PORT},
+    peer_id::{Randomness, DEFAULT_PREFIX},
+};
 use clap::{Parser, Subcommand};
 use std::{net::SocketAddr, path::PathBuf};
 use url::Url;
@@ -7,20 +12,74 @@ use url::Url;
 pub struct Args {
     #[command(subcommand)]
     pub command: Command,
+    /// Client prefix of the peer id we present to trackers and peers
+    #[arg(long, global = true, allow_hyphen_values = true, default_value = DEFAULT_PREFIX)]
+    pub peer_id_prefix: String,
+    /// What fills the rest of the peer id
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub peer_id_random: Randomness,
+    /// Keep this many block requests outstanding per peer instead of
+    /// adapting to each peer's throughput
+    #[arg(long, global = true)]
+    pub queue_depth: Option<usize>,
+    /// Port to accept peer connections on, as announced to trackers
+    #[arg(long, global = true, default_value_t = DEFAULT_PORT)]
+    pub port: u16,
+    /// Most incoming peer connections to keep open at once
+    #[arg(long, global = true, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    pub max_connections: usize,
 }
 
 #[derive(Subcommand)]
 #[clap(rename_all = "snake_case")]
 pub enum Command {
     Decode {
+        /// Bencoded value, or `-` to read raw bytes from stdin
         value: String,
+        /// Render non-UTF-8 byte strings instead of failing
+        #[arg(long, value_enum)]
+        binary: Option<BinaryEncoding>,
+    },
+    Encode {
+        value: String,
+    },
+    /// Pretty-print a bencoded file (or `-` for stdin) as a tree
+    Dump {
+        path: PathBuf,
+    },
+    /// Check that a bencoded file (or `-` for stdin) is in canonical form
+    Validate {
+        path: PathBuf,
     },
     Info {
         torrent: PathBuf,
     },
+    /// Build a .torrent file from a file or directory
+    Create {
+        path: PathBuf,
+        #[arg(short)]
+        output: PathBuf,
+        /// Tracker URL; repeat to add backup trackers
+        #[arg(long, required = true)]
+        announce: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Piece length in bytes, picked from the content size by default
+        #[arg(long)]
+        piece_length: Option<u64>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        source: Option<String>,
+    },
     Peers {
         torrent: PathBuf,
     },
+    /// Ask the trackers of torrent files or magnet links for swarm counts
+    Scrape {
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
     Handshake {
         torrent: PathBuf,
         peer_address: SocketAddr,
@@ -36,6 +95,17 @@ pub enum Command {
         output: PathBuf,
         torrent: PathBuf,
     },
+    /// Upload the verified pieces of existing files to other peers
+    Seed {
+        torrent: PathBuf,
+        /// Where the content is, laid out as `download -o` would write it
+        data: PathBuf,
+    },
+    /// Run tracker services
+    Tracker {
+        #[command(subcommand)]
+        command: TrackerCommand,
+    },
     MagnetParse {
         magnet_link: Url,
     },
@@ -57,3 +127,21 @@ pub enum Command {
         magnet_link: Url,
     },
 }
+
+#[derive(Subcommand)]
+pub enum TrackerCommand {
+    /// Serve HTTP (and optionally UDP) announce and scrape for local swarms
+    Serve {
+        #[arg(long, default_value = "0.0.0.0:6969")]
+        bind: SocketAddr,
+        /// Also serve the BEP 15 UDP protocol on this address
+        #[arg(long)]
+        udp_bind: Option<SocketAddr>,
+        /// Seconds clients wait between announces
+        #[arg(long, default_value_t = 1800)]
+        interval: u64,
+        /// File of hex info hashes, one per line, to track exclusively
+        #[arg(long)]
+        allow_list: Option<PathBuf>,
+    },
+}
@@ -1,8 +1,35 @@
+use anyhow::bail;
 use clap::Parser;
-use commands::commands::{Args, Command};
-use std::{net::SocketAddr, path::PathBuf};
-use tokio::{fs::File, io::AsyncWriteExt};
-use torrent::{decode::decode_bencoded_value, magnet::Magnet, peer::Peer, torrent::Torrent};
+use commands::commands::{Args, Command, TrackerCommand};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    net::{TcpListener, UdpSocket},
+    task::JoinSet,
+};
+use torrent::{
+    create::{create_torrent, CreateOptions},
+    decode::{decode_bencoded_value, dump_bencoded_value},
+    encode::encode_json_value,
+    listener::{self, Listener},
+    magnet::Magnet,
+    parser,
+    peer::Peer,
+    peer_id::PeerId,
+    pipeline,
+    torrent::Torrent,
+    tracker,
+    tracker_server::{read_allow_list, serve_http, serve_udp, sweep_swarms, ServerConfig, Swarms},
+};
+use url::Url;
 
 mod commands;
 mod torrent;
@@ -10,22 +37,75 @@ mod torrent;
 #[tokio::main(worker_threads = 5)]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    PeerId::init_session(PeerId::generate(&args.peer_id_prefix, args.peer_id_random)?)?;
+    if let Some(depth) = args.queue_depth {
+        pipeline::fix_queue_depth(depth)?;
+    }
+    // Commands that advertise our port to trackers or peers listen on it;
+    // connections for torrents we aren't transferring are just closed. A
+    // magnet download has nothing to route incoming peers to, so it doesn't.
+    let listener = match args.command {
+        Command::Peers { .. }
+        | Command::DownloadPiece { .. }
+        | Command::Download { .. }
+        | Command::Seed { .. }
+        | Command::MagnetHandshake { .. }
+        | Command::MagnetInfo { .. }
+        | Command::MagnetDownloadPiece { .. } => listen(args.port, args.max_connections).await,
+        _ => None,
+    };
+    listener::init_port(listener.as_ref().map_or(args.port, Listener::port))?;
 
     match args.command {
-        Command::Decode { value } => {
-            let decoded = decode_bencoded_value(&value)?;
+        Command::Decode { value, binary } => {
+            let encoded = if value == "-" {
+                read_input(Path::new("-"))?
+            } else {
+                value.into_bytes()
+            };
+            let decoded = decode_bencoded_value(&encoded, binary)?;
             println!("{}", decoded);
         }
+        Command::Encode { value } => {
+            let encoded = encode_json_value(&value)?;
+            std::io::stdout().write_all(&encoded)?;
+        }
+        Command::Dump { path } => {
+            print!("{}", dump_bencoded_value(&read_input(&path)?)?);
+        }
+        Command::Validate { path } => {
+            let violations = parser::validate(&read_input(&path)?)?;
+            if !violations.is_empty() {
+                for violation in &violations {
+                    println!("{}", violation);
+                }
+                bail!("{} non-canonical encoding(s) found", violations.len());
+            }
+            println!("OK");
+        }
         Command::Info { torrent } => {
             let torrent = Torrent::new(torrent)?;
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.len());
-            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
-            println!("Piece Length: {}", torrent.info.piece_length);
-            println!("Piece Hashes:");
-            for piece_hash in torrent.pieces() {
-                println!("{}", hex::encode(piece_hash));
-            }
+            print_info(&torrent);
+        }
+        Command::Create {
+            path,
+            output,
+            announce,
+            comment,
+            piece_length,
+            private,
+            source,
+        } => {
+            let options = CreateOptions {
+                announce,
+                comment,
+                piece_length,
+                private,
+                source,
+            };
+            let torrent = create_torrent(&path, &options)?;
+            std::fs::write(&output, serde_bencode::to_bytes(&torrent)?)?;
+            println!("Info Hash: {}", hex::encode(torrent.info_hash()));
         }
         Command::Peers { torrent } => {
             let peer_addrs = discover_peers(torrent).await?;
@@ -33,12 +113,53 @@ async fn main() -> anyhow::Result<()> {
                 println!("{}", addr);
             }
         }
+        Command::Scrape { targets } => {
+            let targets = targets
+                .iter()
+                .map(|target| scrape_target(target))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut by_tracker: BTreeMap<String, Vec<[u8; 20]>> = BTreeMap::new();
+            for (_, info_hash, urls) in &targets {
+                for url in urls {
+                    by_tracker.entry(url.clone()).or_default().push(*info_hash);
+                }
+            }
+
+            // Every tracker at once, so a dead one holds up none of the others.
+            let mut scrapes = JoinSet::new();
+            for (url, info_hashes) in by_tracker {
+                scrapes.spawn(async move {
+                    let stats = tracker::scrape(&url, &info_hashes).await;
+                    (url, stats)
+                });
+            }
+            let mut results = HashMap::new();
+            while let Some(join_result) = scrapes.join_next().await {
+                match join_result? {
+                    (url, Ok(stats)) => {
+                        results.extend(stats.into_iter().map(|(h, s)| ((url.clone(), h), s)))
+                    }
+                    (url, Err(e)) => eprintln!("{} -> {}", url, e),
+                }
+            }
+            for (name, info_hash, urls) in &targets {
+                println!("{} {}", hex::encode(info_hash), name);
+                for url in urls {
+                    if let Some(stats) = results.get(&(url.clone(), *info_hash)) {
+                        println!(
+                            "  {}: {} seeders, {} completed, {} leechers",
+                            url, stats.seeders, stats.completed, stats.leechers
+                        );
+                    }
+                }
+            }
+        }
         Command::Handshake {
             torrent,
             peer_address,
         } => {
             let peer = handshake(torrent, peer_address).await?;
-            println!("Peer ID: {}", hex::encode(&peer.id));
+            println!("Peer ID: {}", hex::encode(peer.id));
         }
         Command::DownloadPiece {
             output,
@@ -52,9 +173,38 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Download { output, torrent } => {
             let torrent = Torrent::new(torrent)?;
-            let file_bytes = torrent.download().await?;
-            let mut file = File::create(output).await?;
-            file.write_all(&file_bytes).await?;
+            torrent.download(&output, listener.as_ref()).await?;
+        }
+        Command::Seed { torrent, data } => {
+            let torrent = Torrent::new(torrent)?;
+            torrent.seed(&data, listener.as_ref()).await?;
+        }
+        Command::Tracker {
+            command:
+                TrackerCommand::Serve {
+                    bind,
+                    udp_bind,
+                    interval,
+                    allow_list,
+                },
+        } => {
+            let mut config = ServerConfig::new(Duration::from_secs(interval));
+            config.allow_list = allow_list.as_deref().map(read_allow_list).transpose()?;
+            let swarms = Arc::new(Swarms::new(config));
+            tokio::spawn(sweep_swarms(swarms.clone()));
+            let listener = TcpListener::bind(bind).await?;
+            println!("Tracker listening on http://{}/announce", bind);
+            match udp_bind {
+                Some(udp_bind) => {
+                    let socket = UdpSocket::bind(udp_bind).await?;
+                    println!("Tracker listening on udp://{}/announce", udp_bind);
+                    tokio::try_join!(
+                        serve_http(listener, swarms.clone()),
+                        serve_udp(socket, swarms)
+                    )?;
+                }
+                None => serve_http(listener, swarms).await?,
+            }
         }
         Command::MagnetParse { magnet_link } => {
             let magnet = Magnet::new(magnet_link)?;
@@ -64,7 +214,7 @@ async fn main() -> anyhow::Result<()> {
         Command::MagnetHandshake { magnet_link } => {
             let magnet = Magnet::new(magnet_link)?;
             let peer = magnet.handshake().await?;
-            println!("Peer ID: {}", hex::encode(&peer.id));
+            println!("Peer ID: {}", hex::encode(peer.id));
             println!(
                 "Peer Metadata Extension ID: {}",
                 peer.metadata_extension_id.unwrap()
@@ -75,14 +225,7 @@ async fn main() -> anyhow::Result<()> {
             let mut peer = magnet.handshake().await?;
             let metadata = peer.extension_metadata().await?;
             let torrent = Torrent::from_magnet_and_metadata(magnet, metadata)?;
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.len());
-            println!("Info Hash: {}", hex::encode(torrent.info_hash()?));
-            println!("Piece Length: {}", torrent.info.piece_length);
-            println!("Piece Hashes:");
-            for piece_hash in torrent.pieces() {
-                println!("{}", hex::encode(piece_hash));
-            }
+            print_info(&torrent);
         }
         Command::MagnetDownloadPiece {
             output,
@@ -99,15 +242,93 @@ async fn main() -> anyhow::Result<()> {
             magnet_link,
         } => {
             let magnet = Magnet::new(magnet_link)?;
-            let file_bytes = magnet.download().await?;
-            let mut file = File::create(output).await?;
-            file.write_all(&file_bytes).await?;
+            magnet.download(&output).await?;
         }
     }
 
     Ok(())
 }
 
+fn print_info(torrent: &Torrent) {
+    println!("Tracker URL: {}", torrent.announce);
+    println!("Length: {}", torrent.len());
+    println!("Info Hash: {}", hex::encode(torrent.info_hash()));
+    println!("Piece Length: {}", torrent.info.piece_length);
+    if let Some(tiers) = &torrent.announce_list {
+        println!("Announce List:");
+        for (i, tier) in tiers.iter().enumerate() {
+            println!("  Tier {}: {}", i + 1, tier.join(", "));
+        }
+    }
+    if let Some(comment) = &torrent.comment {
+        println!("Comment: {}", String::from_utf8_lossy(comment));
+    }
+    if let Some(created_by) = &torrent.created_by {
+        println!("Created By: {}", String::from_utf8_lossy(created_by));
+    }
+    if let Some(creation_date) = torrent.creation_date {
+        println!("Creation Date: {}", creation_date);
+    }
+    if let Some(private) = torrent.info.private {
+        println!("Private: {}", private == 1);
+    }
+    if let Some(source) = &torrent.info.source {
+        println!("Source: {}", String::from_utf8_lossy(source));
+    }
+    if let Some(url_list) = &torrent.url_list {
+        println!("Web Seeds: {}", url_list.urls().join(", "));
+    }
+    let extra_keys = torrent.extra.keys().chain(torrent.info.extra.keys());
+    let extra_keys = extra_keys
+        .map(|key| String::from_utf8_lossy(key))
+        .collect::<Vec<_>>();
+    if !extra_keys.is_empty() {
+        println!("Other Keys: {}", extra_keys.join(", "));
+    }
+    println!("Piece Hashes:");
+    for piece_hash in torrent.pieces() {
+        println!("{}", hex::encode(piece_hash));
+    }
+}
+
+/// Reads a whole file, or stdin when `path` is `-`.
+fn read_input(path: &Path) -> anyhow::Result<Vec<u8>> {
+    if path == Path::new("-") {
+        let mut buf = Vec::new();
+        std::io::stdin().read_to_end(&mut buf)?;
+        Ok(buf)
+    } else {
+        Ok(std::fs::read(path)?)
+    }
+}
+
+/// Resolves a torrent path or magnet link to a display name, its info hash
+/// and its tracker URLs.
+fn scrape_target(target: &str) -> anyhow::Result<(String, [u8; 20], Vec<String>)> {
+    if target.starts_with("magnet:") {
+        let magnet = Magnet::new(Url::parse(target)?)?;
+        let name = magnet.file_name.clone().unwrap_or(target.to_string());
+        let urls = magnet.tracker_url.iter().map(Url::to_string).collect();
+        Ok((name, magnet.info_hash, urls))
+    } else {
+        let torrent = Torrent::new(PathBuf::from(target))?;
+        let urls = torrent.tracker_urls();
+        Ok((torrent.info.name().to_string(), torrent.info_hash(), urls))
+    }
+}
+
+/// Without a listener we can still connect out, so failing to bind is only
+/// worth a warning.
+async fn listen(port: u16, max_connections: usize) -> Option<Listener> {
+    match Listener::bind(port, max_connections).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            eprintln!("Not accepting peer connections: {}", e);
+            None
+        }
+    }
+}
+
 async fn discover_peers(file_name: PathBuf) -> anyhow::Result<Vec<SocketAddr>> {
     let torrent = Torrent::new(file_name)?;
     let peer_addrs = torrent.get_peer_addrs().await?;
@@ -116,6 +337,6 @@ async fn discover_peers(file_name: PathBuf) -> anyhow::Result<Vec<SocketAddr>> {
 
 async fn handshake(file_name: PathBuf, peer_address: SocketAddr) -> anyhow::Result<Peer> {
     let torrent = Torrent::new(file_name)?;
-    let peer = Peer::new(peer_address, torrent.info_hash()?).await?;
+    let peer = Peer::new(peer_address, torrent.info_hash()).await?;
     Ok(peer)
 }
@@ -0,0 +1,233 @@
+use crate::torrent::tracker::{Announce, Event, TrackerRequest, TrackerTiers};
+use anyhow::{Context, Result};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+/// Used when trackers don't say how often to re-announce.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// How long shutdown waits for `stopped` to go out.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Byte counters reported to trackers, updated by the transfer as it goes.
+#[derive(Debug, Default)]
+pub struct Transfer {
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+    pub left: AtomicU64,
+}
+
+impl Transfer {
+    pub fn new(left: u64) -> Arc<Self> {
+        Arc::new(Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        })
+    }
+
+    fn request(&self, event: Option<Event>) -> TrackerRequest {
+        let mut request = TrackerRequest::new(self.left.load(Ordering::Relaxed));
+        request.uploaded = self.uploaded.load(Ordering::Relaxed);
+        request.downloaded = self.downloaded.load(Ordering::Relaxed);
+        request.event = event;
+        request
+    }
+}
+
+enum Command {
+    Completed,
+    Stop,
+}
+
+/// Keeps a torrent announced for as long as it is being transferred:
+/// `started` first, then re-announces on the trackers' interval, `completed`
+/// once every piece is verified and `stopped` on shutdown.
+pub struct Announcer {
+    commands: mpsc::UnboundedSender<Command>,
+    task: JoinHandle<()>,
+    peers: watch::Receiver<Vec<SocketAddr>>,
+}
+
+impl Announcer {
+    /// Sends `started` and returns the peers it found, leaving the announcer
+    /// running in the background.
+    pub async fn start(
+        trackers: Arc<Mutex<TrackerTiers>>,
+        info_hash: [u8; 20],
+        transfer: Arc<Transfer>,
+    ) -> Result<(Self, Vec<SocketAddr>)> {
+        let started = trackers
+            .lock()
+            .await
+            .announce(&info_hash, &transfer.request(Some(Event::Started)))
+            .await?;
+        let peers = started.peers.clone();
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (peers_tx, peers_rx) = watch::channel(peers.clone());
+        let task = tokio::spawn(run(
+            trackers,
+            info_hash,
+            transfer,
+            next_announce(&started),
+            receiver,
+            peers_tx,
+        ));
+        let announcer = Self {
+            commands,
+            task,
+            peers: peers_rx,
+        };
+        Ok((announcer, peers))
+    }
+
+    /// The peers from the latest announce, updated as re-announces come in.
+    pub fn peers(&self) -> watch::Receiver<Vec<SocketAddr>> {
+        self.peers.clone()
+    }
+
+    pub fn completed(&self) {
+        let _ = self.commands.send(Command::Completed);
+    }
+
+    /// Sends `stopped` and waits up to [`STOP_TIMEOUT`] for it to go out;
+    /// unresponsive trackers are given up on rather than holding up exit.
+    pub async fn stop(mut self) -> Result<()> {
+        let _ = self.commands.send(Command::Stop);
+        match timeout(STOP_TIMEOUT, &mut self.task).await {
+            Ok(result) => result.context("announcer panicked"),
+            Err(_) => {
+                self.task.abort();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Waits the longer of `interval` and `min interval` before re-announcing.
+fn next_announce(announce: &Announce) -> Duration {
+    match announce.interval.max(announce.min_interval) {
+        Some(secs) => Duration::from_secs(secs as u64),
+        None => DEFAULT_INTERVAL,
+    }
+}
+
+async fn run(
+    trackers: Arc<Mutex<TrackerTiers>>,
+    info_hash: [u8; 20],
+    transfer: Arc<Transfer>,
+    mut wait: Duration,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    peers: watch::Sender<Vec<SocketAddr>>,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = sleep(wait) => None,
+            command = commands.recv() => match command {
+                Some(Command::Completed) => Some(Event::Completed),
+                Some(Command::Stop) | None => Some(Event::Stopped),
+            },
+        };
+        match trackers
+            .lock()
+            .await
+            .announce(&info_hash, &transfer.request(event))
+            .await
+        {
+            Ok(announce) => {
+                wait = next_announce(&announce);
+                peers.send_replace(announce.peers);
+            }
+            Err(e) => eprintln!("Announce failed: {}", e),
+        }
+        if event == Some(Event::Stopped) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::testing::spawn_tcp;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers HTTP announces with a one-second interval, passing each
+    /// request's query string on.
+    async fn stand_in() -> (String, mpsc::UnboundedReceiver<String>) {
+        let (queries, receiver) = mpsc::unbounded_channel();
+        let address = spawn_tcp("127.0.0.1:0", |listener| async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let path = request.split(' ').nth(1).unwrap();
+                queries
+                    .send(path.split('?').nth(1).unwrap().to_string())
+                    .unwrap();
+
+                let body = b"d8:intervali1e12:min intervali1e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        })
+        .await;
+        (format!("http://{address}/announce"), receiver)
+    }
+
+    fn param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    #[tokio::test]
+    async fn test_announce_lifecycle() {
+        let (url, mut queries) = stand_in().await;
+        let transfer = Transfer::new(1000);
+        let trackers = Arc::new(Mutex::new(TrackerTiers::new(vec![vec![url]])));
+        let (announcer, peers) = Announcer::start(trackers, [5; 20], transfer.clone())
+            .await
+            .unwrap();
+        assert_eq!(peers, ["127.0.0.1:6881".parse().unwrap()]);
+
+        let started = queries.recv().await.unwrap();
+        assert_eq!(param(&started, "event"), Some("started"));
+        assert_eq!(param(&started, "left"), Some("1000"));
+
+        transfer.downloaded.store(400, Ordering::Relaxed);
+        transfer.left.store(600, Ordering::Relaxed);
+        let periodic = queries.recv().await.unwrap();
+        assert_eq!(param(&periodic, "event"), None);
+        assert_eq!(param(&periodic, "downloaded"), Some("400"));
+        let mut peers = announcer.peers();
+        peers.changed().await.unwrap();
+        assert_eq!(*peers.borrow(), ["127.0.0.1:6881".parse().unwrap()]);
+
+        transfer.downloaded.store(1000, Ordering::Relaxed);
+        transfer.left.store(0, Ordering::Relaxed);
+        announcer.completed();
+        let completed = queries.recv().await.unwrap();
+        assert_eq!(param(&completed, "event"), Some("completed"));
+        assert_eq!(param(&completed, "left"), Some("0"));
+
+        announcer.stop().await.unwrap();
+        let stopped = queries.recv().await.unwrap();
+        assert_eq!(param(&stopped, "event"), Some("stopped"));
+        assert_eq!(param(&stopped, "downloaded"), Some("1000"));
+    }
+}
@@ -0,0 +1,224 @@
+use crate::torrent::{
+    storage::Storage,
+    torrent::{Info, Torrent},
+};
+use anyhow::{anyhow, ensure, Context, Result};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024; // 16 KiB
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024; // 16 MiB
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+pub struct CreateOptions {
+    /// Tracker URLs; the first becomes `announce`, and with more than one
+    /// each gets its own `announce-list` tier so they are tried in order.
+    pub announce: Vec<String>,
+    pub comment: Option<String>,
+    /// Picked from the content size when not given.
+    pub piece_length: Option<u64>,
+    pub private: bool,
+    pub source: Option<String>,
+}
+
+/// Builds a torrent for the file or directory at `path`, hashing its pieces
+/// on all available cores.
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Torrent> {
+    let announce = options
+        .announce
+        .first()
+        .ok_or(anyhow!("at least one announce URL is required"))?;
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(anyhow!("{} has no usable file name", path.display()))?
+        .to_string();
+
+    let mut info = if path.is_dir() {
+        let files = walk_dir(&path)?;
+        ensure!(!files.is_empty(), "{} contains no files", path.display());
+        let total = files.iter().map(|(_, length)| length).sum();
+        let piece_length = options.piece_length.unwrap_or(pick_piece_length(total));
+        Info::multi_file(name, files, piece_length)
+    } else {
+        let length = fs::metadata(&path)?.len();
+        let piece_length = options.piece_length.unwrap_or(pick_piece_length(length));
+        Info::single_file(name, length, piece_length)
+    };
+    ensure!(
+        info.piece_length > 0 && info.piece_length <= u32::MAX as u64,
+        "piece length must be between 1 and {} bytes",
+        u32::MAX
+    );
+    if options.private {
+        info.private = Some(1);
+    }
+    info.source = options.source.clone().map(ByteBuf::from);
+
+    // A multi-file layout is `<parent>/<name>/...`, which is the directory itself.
+    let storage_root = match path.parent() {
+        Some(parent) if path.is_dir() => parent.to_path_buf(),
+        _ => path.clone(),
+    };
+    let storage = Storage::new(&info, &storage_root)?;
+    info.pieces = hash_pieces(&info, &storage)?;
+
+    let mut torrent = Torrent::from_info(announce.clone(), info)?;
+    if options.announce.len() > 1 {
+        let tiers = options.announce.iter().map(|url| vec![url.clone()]);
+        torrent.announce_list = Some(tiers.collect());
+    }
+    torrent.comment = options.comment.clone().map(ByteBuf::from);
+    torrent.created_by = Some(ByteBuf::from(format!(
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )));
+    torrent.creation_date = Some(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64);
+    Ok(torrent)
+}
+
+/// Aims for roughly [`TARGET_PIECE_COUNT`] pieces, rounded to a power of two.
+fn pick_piece_length(total: u64) -> u64 {
+    (total / TARGET_PIECE_COUNT)
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+/// Lists regular files below `root` as (path components, length), sorted by
+/// path so the same tree always yields the same torrent.
+fn walk_dir(root: &Path) -> Result<Vec<(Vec<String>, u64)>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(root.join(&relative))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let relative = relative.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(relative);
+            } else if file_type.is_file() {
+                let components = relative
+                    .iter()
+                    .map(|c| c.to_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(anyhow!("{} is not valid UTF-8", relative.display()))?;
+                files.push((components, entry.metadata()?.len()));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn hash_pieces(info: &Info, storage: &Storage) -> Result<Vec<u8>> {
+    let num_pieces = info.file_len().div_ceil(info.piece_length) as usize;
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+    // Worker `w` hashes pieces w, w + workers, w + 2 * workers, ...
+    let hashed = thread::scope(|scope| {
+        let handles = (0..workers)
+            .map(|worker| {
+                scope.spawn(move || {
+                    (worker..num_pieces)
+                        .step_by(workers)
+                        .map(|piece| {
+                            let offset = info.piece_offset(piece)?;
+                            let data = storage.read(offset, info.piece_len(piece)? as usize)?;
+                            Ok((piece, <[u8; 20]>::from(Sha1::digest(&data))))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("hashing thread panicked"))?
+            })
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    let mut pieces = vec![0u8; num_pieces * 20];
+    for (piece, hash) in hashed.into_iter().flatten() {
+        pieces[piece * 20..][..20].copy_from_slice(&hash);
+    }
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::testing::create_options;
+
+    fn options() -> CreateOptions {
+        CreateOptions {
+            announce: vec!["http://a/announce".to_string(), "udp://b:80".to_string()],
+            comment: Some("build 42".to_string()),
+            private: true,
+            source: Some("CI".to_string()),
+            ..create_options(4)
+        }
+    }
+
+    #[test]
+    fn test_pick_piece_length() {
+        assert_eq!(pick_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(pick_piece_length(1500 * 1024 * 1024), 1024 * 1024);
+        assert_eq!(pick_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_create_multi_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("release");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("README"), b"hello").unwrap();
+        fs::write(root.join("bin").join("tool"), b"0123456").unwrap();
+
+        let torrent = create_torrent(&root, &options()).unwrap();
+        let files = torrent.info.files().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0], (["README".to_string()].as_slice(), 5));
+        assert_eq!(files[1].0, ["bin", "tool"]);
+
+        // "hell" "o012" "3456" across the two files.
+        let expected = [b"hell".as_slice(), b"o012", b"3456"]
+            .map(|piece| Sha1::digest(piece).to_vec())
+            .concat();
+        assert_eq!(torrent.info.pieces, expected);
+        assert_eq!(torrent.info.private, Some(1));
+        assert_eq!(torrent.announce_list.as_ref().unwrap().len(), 2);
+
+        // What we write must read back with the same info hash.
+        let encoded = serde_bencode::to_bytes(&torrent).unwrap();
+        let parsed = Torrent::from_bytes(&encoded).unwrap();
+        assert_eq!(parsed.info_hash(), torrent.info_hash());
+        assert_eq!(parsed.comment, Some(ByteBuf::from("build 42")));
+    }
+
+    #[test]
+    fn test_create_single_file_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("artifact.bin");
+        fs::write(&path, b"abcdefghij").unwrap();
+
+        let torrent = create_torrent(&path, &options()).unwrap();
+        assert!(torrent.info.files().is_none());
+        assert_eq!(torrent.info.name(), "artifact.bin");
+        assert_eq!(torrent.len(), 10);
+        assert_eq!(torrent.pieces().len(), 3);
+        assert_eq!(torrent.pieces()[2], Sha1::digest(b"ij").to_vec());
+    }
+}
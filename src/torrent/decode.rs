@@ -1,32 +1,175 @@
+use crate::torrent::parser::{self, Node, NodeValue};
 use anyhow::Result;
-use serde_bencode::{from_str, value::Value as BencodedValue};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
 use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::fmt::Write;
 
-pub fn decode_bencoded_value(encoded_value: &str) -> Result<Value> {
-    let value = from_str(encoded_value)?;
-    let decoded = bencode_to_json(value)?;
+const PREVIEW_TEXT_LEN: usize = 64;
+const PREVIEW_BINARY_LEN: usize = 16;
+
+/// How byte strings that are not valid UTF-8 are rendered in the JSON output.
+///
+/// Rendered strings carry a `hex:` or `base64:` prefix so they can be told
+/// apart from byte strings that happened to be valid text.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum BinaryEncoding {
+    Hex,
+    Base64,
+}
+
+impl BinaryEncoding {
+    fn render(&self, bytes: &[u8]) -> String {
+        match self {
+            BinaryEncoding::Hex => format!("hex:{}", hex::encode(bytes)),
+            BinaryEncoding::Base64 => format!("base64:{}", STANDARD.encode(bytes)),
+        }
+    }
+}
+
+/// Decodes bencode into JSON. Without a `binary` encoding any non-UTF-8 byte
+/// string is an error.
+pub fn decode_bencoded_value(
+    encoded_value: &[u8],
+    binary: Option<BinaryEncoding>,
+) -> Result<Value> {
+    let node = parser::parse(encoded_value)?;
+    let decoded = bencode_to_json(&node, binary)?;
 
     Ok(decoded)
 }
 
-fn bencode_to_json(value: BencodedValue) -> Result<Value> {
-    match value {
-        BencodedValue::Bytes(b) => Ok(Value::String(String::from_utf8(b)?)),
-        BencodedValue::Int(i) => Ok(Value::Number(serde_json::Number::from(i))),
-        BencodedValue::List(l) => {
+fn bytes_to_string(bytes: &[u8], binary: Option<BinaryEncoding>) -> Result<String> {
+    match (std::str::from_utf8(bytes), binary) {
+        (Ok(s), _) => Ok(s.to_string()),
+        (Err(_), Some(binary)) => Ok(binary.render(bytes)),
+        (Err(e), None) => Err(e.into()),
+    }
+}
+
+fn bencode_to_json(node: &Node, binary: Option<BinaryEncoding>) -> Result<Value> {
+    match &node.value {
+        NodeValue::Bytes(b) => Ok(Value::String(bytes_to_string(b, binary)?)),
+        NodeValue::Int(i) => Ok(Value::Number(serde_json::Number::from(*i))),
+        NodeValue::List(l) => {
             let json_list = l
-                .into_iter()
-                .map(|v| bencode_to_json(v))
+                .iter()
+                .map(|v| bencode_to_json(v, binary))
                 .collect::<Result<Vec<Value>>>()?;
 
             Ok(Value::Array(json_list))
         }
-        BencodedValue::Dict(d) => {
+        NodeValue::Dict(d) => {
             let json_map = d
-                .into_iter()
-                .map(|(k, v)| Ok((String::from_utf8(k)?, bencode_to_json(v)?)))
+                .iter()
+                .map(|(k, v)| Ok((bytes_to_string(k, binary)?, bencode_to_json(v, binary)?)))
                 .collect::<Result<serde_json::Map<String, Value>>>()?;
             Ok(Value::Object(json_map))
         }
     }
 }
+
+/// Renders bencoded input as an indented tree, one value per line, with
+/// types, lengths and short previews of byte strings. Values of the top-level
+/// dictionary also show the SHA-1 of their raw encoding.
+pub fn dump_bencoded_value(encoded_value: &[u8]) -> Result<String> {
+    let node = parser::parse(encoded_value)?;
+    let mut out = String::new();
+    dump_node(&mut out, encoded_value, &node, 0)?;
+
+    Ok(out)
+}
+
+fn dump_node(out: &mut String, input: &[u8], node: &Node, depth: usize) -> Result<()> {
+    match &node.value {
+        NodeValue::Bytes(b) => writeln!(out, "bytes[{}] {}", b.len(), preview(b))?,
+        NodeValue::Int(i) => writeln!(out, "int {}", i)?,
+        NodeValue::List(l) => {
+            writeln!(out, "list[{}]", l.len())?;
+            for (i, v) in l.iter().enumerate() {
+                write!(out, "{:indent$}[{}]: ", "", i, indent = (depth + 1) * 2)?;
+                dump_node(out, input, v, depth + 1)?;
+            }
+        }
+        NodeValue::Dict(d) => {
+            writeln!(out, "dict[{}]", d.len())?;
+            for (k, v) in d {
+                write!(
+                    out,
+                    "{:indent$}{}: ",
+                    "",
+                    preview(k),
+                    indent = (depth + 1) * 2
+                )?;
+                if depth == 0 {
+                    let hash = Sha1::digest(v.raw(input));
+                    write!(out, "(sha1 {}) ", hex::encode(hash))?;
+                }
+                dump_node(out, input, v, depth + 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Quotes text, and shows binary data as a `<hex>` prefix, both truncated.
+fn preview(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if !s.chars().any(char::is_control) => match s.char_indices().nth(PREVIEW_TEXT_LEN) {
+            Some((end, _)) => format!("{:?}...", &s[..end]),
+            None => format!("{:?}", s),
+        },
+        _ if bytes.len() > PREVIEW_BINARY_LEN => {
+            format!("<{}...>", hex::encode(&bytes[..PREVIEW_BINARY_LEN]))
+        }
+        _ => format!("<{}>", hex::encode(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_rejects_binary_by_default() {
+        assert!(decode_bencoded_value(b"2:\xff\x00", None).is_err());
+        assert_eq!(
+            decode_bencoded_value(b"5:hello", None).unwrap(),
+            Value::from("hello")
+        );
+    }
+
+    #[test]
+    fn test_decode_renders_binary_strings() {
+        let encoded = b"d5:peers6:\x7f\x00\x00\x01\x1a\xe14:name4:teste";
+        let hex = decode_bencoded_value(encoded, Some(BinaryEncoding::Hex)).unwrap();
+        assert_eq!(hex["peers"], "hex:7f0000011ae1");
+        assert_eq!(hex["name"], "test");
+
+        let base64 = decode_bencoded_value(encoded, Some(BinaryEncoding::Base64)).unwrap();
+        assert_eq!(base64["peers"], "base64:fwAAARrh");
+    }
+
+    #[test]
+    fn test_decode_reports_parse_errors() {
+        let err = decode_bencoded_value(b"l5:helloi52", None).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected end of input at byte 11");
+    }
+
+    #[test]
+    fn test_dump_renders_tree() {
+        let encoded = b"d4:infod6:lengthi7e6:pieces20:\x00\x01\x02\x03\x04\x05\x06\x07\x08\x09\x0a\x0b\x0c\x0d\x0e\x0f\x10\x11\x12\x13e4:listl1:aee";
+        let dump = dump_bencoded_value(encoded).unwrap();
+        let expected = [
+            "dict[2]",
+            "  \"info\": (sha1 7cc53f11adad35372b85e593eba819e05503a7ca) dict[2]",
+            "    \"length\": int 7",
+            "    \"pieces\": bytes[20] <000102030405060708090a0b0c0d0e0f...>",
+            "  \"list\": (sha1 c06e03b189fc11ed7517ad79c73c37c618eb3569) list[1]",
+            "    [0]: bytes[1] \"a\"",
+        ];
+        assert_eq!(dump.lines().collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Result};
+use serde_bencode::value::Value as BencodedValue;
+use serde_json::Value;
+
+pub fn encode_json_value(json_value: &str) -> Result<Vec<u8>> {
+    let value = serde_json::from_str(json_value)?;
+    let bencoded = json_to_bencode(value)?;
+
+    Ok(encode_value(&bencoded))
+}
+
+/// Encodes a value tree as canonical bencode: dictionary keys are written in
+/// sorted (raw byte) order regardless of how the tree stores them.
+pub fn encode_value(value: &BencodedValue) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    write_value(&mut encoded, value);
+    encoded
+}
+
+fn write_value(out: &mut Vec<u8>, value: &BencodedValue) {
+    match value {
+        BencodedValue::Bytes(b) => write_bytes(out, b),
+        BencodedValue::Int(i) => {
+            out.push(b'i');
+            out.extend(i.to_string().as_bytes());
+            out.push(b'e');
+        }
+        BencodedValue::List(l) => {
+            out.push(b'l');
+            for v in l {
+                write_value(out, v);
+            }
+            out.push(b'e');
+        }
+        BencodedValue::Dict(d) => {
+            let mut entries = d.iter().collect::<Vec<_>>();
+            entries.sort_by_key(|(k, _)| *k);
+
+            out.push(b'd');
+            for (k, v) in entries {
+                write_bytes(out, k);
+                write_value(out, v);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend(bytes);
+}
+
+fn json_to_bencode(value: Value) -> Result<BencodedValue> {
+    match value {
+        Value::String(s) => Ok(BencodedValue::Bytes(s.into_bytes())),
+        Value::Number(n) => n
+            .as_i64()
+            .map(BencodedValue::Int)
+            .ok_or(anyhow!("bencode only supports 64-bit integers, got {}", n)),
+        Value::Array(a) => {
+            let bencoded_list = a
+                .into_iter()
+                .map(json_to_bencode)
+                .collect::<Result<Vec<BencodedValue>>>()?;
+
+            Ok(BencodedValue::List(bencoded_list))
+        }
+        Value::Object(o) => {
+            let bencoded_map = o
+                .into_iter()
+                .map(|(k, v)| Ok((k.into_bytes(), json_to_bencode(v)?)))
+                .collect::<Result<_>>()?;
+            Ok(BencodedValue::Dict(bencoded_map))
+        }
+        Value::Bool(_) | Value::Null => Err(anyhow!("bencode has no {} type", value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_encode_scalars() {
+        assert_eq!(encode_json_value(r#""hello""#).unwrap(), b"5:hello");
+        assert_eq!(encode_json_value("-52").unwrap(), b"i-52e");
+        assert_eq!(encode_json_value(r#""""#).unwrap(), b"0:");
+    }
+
+    #[test]
+    fn test_encode_sorts_dictionary_keys() {
+        let encoded = encode_json_value(r#"{"peers": ["a", 1], "interval": 60}"#).unwrap();
+        assert_eq!(encoded, b"d8:intervali60e5:peersl1:ai1eee");
+
+        let dict = HashMap::from([
+            (b"zz".to_vec(), BencodedValue::Int(1)),
+            (b"a".to_vec(), BencodedValue::Bytes(vec![0xff])),
+        ]);
+        assert_eq!(
+            encode_value(&BencodedValue::Dict(dict)),
+            b"d1:a1:\xff2:zzi1ee"
+        );
+    }
+
+    #[test]
+    fn test_encode_rejects_unrepresentable_json() {
+        assert!(encode_json_value("1.5").is_err());
+        assert!(encode_json_value("true").is_err());
+        assert!(encode_json_value(r#"{"a": null}"#).is_err());
+    }
+
+    #[test]
+    fn test_encode_round_trips_through_serde_bencode() {
+        let encoded = encode_json_value(r#"{"m": {"ut_metadata": 1}, "p": 6881}"#).unwrap();
+        let decoded = serde_bencode::from_bytes::<BencodedValue>(&encoded).unwrap();
+        assert_eq!(encode_value(&decoded), encoded);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::torrent::{listener, seed::MAX_QUEUED_REQUESTS};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::mem;
@@ -7,6 +8,9 @@ pub struct ExtensionHeader {
     pub m: ExtensionMetadata,
     p: Option<u16>, // port
     metadata_size: u32,
+    /// How many outstanding requests the sender queues before dropping more.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -21,13 +25,14 @@ impl ExtensionHeader {
             ut_metadata: 1,
             ut_pex: Some(2),
         };
-        let port = Some(6881);
+        let port = Some(listener::port());
         let size = (mem::size_of_val(&metadata) + mem::size_of_val(&port)) as u32;
 
         Self {
             m: metadata,
             p: port,
             metadata_size: size,
+            reqq: Some(MAX_QUEUED_REQUESTS as u32),
         }
     }
 }
@@ -0,0 +1,238 @@
+use crate::torrent::peer::{Handshake, Peer};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+/// The port we listen on and advertise, unless set with [`init_port`].
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_MAX_CONNECTIONS: usize = 50;
+
+/// How long a connecting peer has to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after a failed accept, so errors that persist (such as running out
+/// of file descriptors) don't keep us spinning.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+static PORT: OnceLock<u16> = OnceLock::new();
+
+/// Fixes the port advertised to trackers and peers; must happen before
+/// anything asks for it.
+pub fn init_port(port: u16) -> Result<()> {
+    PORT.set(port)
+        .map_err(|_| anyhow!("listen port is already set"))
+}
+
+pub fn port() -> u16 {
+    *PORT.get_or_init(|| DEFAULT_PORT)
+}
+
+/// Where each torrent we are transferring wants its incoming peers.
+type Routes = Arc<Mutex<HashMap<[u8; 20], mpsc::UnboundedSender<Peer>>>>;
+
+/// Accepts incoming peer connections, answers their handshake and hands
+/// each to the torrent it asked for. Connections for torrents nobody
+/// registered are closed, as are any beyond the connection limit.
+pub struct Listener {
+    port: u16,
+    routes: Routes,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Listens on `port` on every IPv6 and IPv4 address. Where the system
+    /// binds IPv6 sockets dual-stack the IPv4 bind fails and isn't needed;
+    /// it is an error only if neither family can be bound.
+    pub async fn bind(port: u16, max_connections: usize) -> Result<Self> {
+        let mut listeners = vec![];
+        let mut port = port;
+        for ip in [Ipv6Addr::UNSPECIFIED.into(), Ipv4Addr::UNSPECIFIED.into()] {
+            if let Ok(listener) = TcpListener::bind(SocketAddr::new(ip, port)).await {
+                // Later binds reuse the port the system picked for port 0.
+                port = listener.local_addr()?.port();
+                listeners.push(listener);
+            }
+        }
+        ensure!(!listeners.is_empty(), "failed to listen on port {}", port);
+
+        let routes = Routes::default();
+        let limit = Arc::new(Semaphore::new(max_connections));
+        let tasks = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(accept(listener, routes.clone(), limit.clone())))
+            .collect();
+        Ok(Self {
+            port,
+            routes,
+            tasks,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Peers connecting for `info_hash` arrive on the returned channel with
+    /// the handshake done, until it is dropped.
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::UnboundedReceiver<Peer> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.routes.lock().unwrap().insert(info_hash, sender);
+        receiver
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+async fn accept(listener: TcpListener, routes: Routes, limit: Arc<Semaphore>) {
+    loop {
+        let (stream, address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("failed to accept a peer: {}", e);
+                sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        // IPv4 peers reaching a dual-stack socket show up as mapped addresses.
+        let address = SocketAddr::new(address.ip().to_canonical(), address.port());
+        // Past the limit the stream is dropped, closing the connection.
+        let Ok(permit) = limit.clone().try_acquire_owned() else {
+            continue;
+        };
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            match timeout(HANDSHAKE_TIMEOUT, respond(stream, address, &routes)).await {
+                Ok(Ok(closed)) => closed.await,
+                Ok(Err(e)) => eprintln!("{} -> {}", address, e),
+                Err(_) => eprintln!("{} -> handshake timed out", address),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// The responder side of the handshake: the peer names the torrent, and we
+/// answer only if it is one of ours. Returns a future that resolves when
+/// the connection is gone.
+async fn respond(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    routes: &Routes,
+) -> Result<impl Future<Output = ()>> {
+    let mut handshake_bytes = [0u8; 68];
+    stream
+        .read_exact(&mut handshake_bytes)
+        .await
+        .context("failed to receive handshake")?;
+    let handshake: Handshake = bincode::deserialize(&handshake_bytes)?;
+    ensure!(
+        handshake.length == 19 && &handshake.protocol == b"BitTorrent protocol",
+        "not a BitTorrent handshake"
+    );
+    let info_hash = handshake.info_hash;
+    let route = routes
+        .lock()
+        .unwrap()
+        .get(&info_hash)
+        .cloned()
+        .with_context(|| format!("unknown info hash {}", hex::encode(info_hash)))?;
+    if route.is_closed() {
+        routes.lock().unwrap().remove(&info_hash);
+        bail!("torrent {} is no longer active", hex::encode(info_hash));
+    }
+
+    let reply = bincode::serialize(&Handshake::new(info_hash))?;
+    stream
+        .write_all(&reply)
+        .await
+        .context("failed to send handshake")?;
+    let peer = Peer::from_stream(address, &handshake, stream);
+    let closed = peer.closed();
+    if route.send(peer).is_err() {
+        routes.lock().unwrap().remove(&info_hash);
+        bail!("torrent {} is no longer active", hex::encode(info_hash));
+    }
+    Ok(closed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::{message::Message, peer_id::PeerId};
+
+    async fn connect(ip: &str, port: u16, info_hash: [u8; 20]) -> Result<Peer> {
+        Peer::new(SocketAddr::new(ip.parse().unwrap(), port), info_hash).await
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_info_hash() {
+        let listener = Listener::bind(0, 10).await.unwrap();
+        let mut first = listener.register([1; 20]);
+        let mut second = listener.register([2; 20]);
+
+        // Both address families reach the listener.
+        let client = connect("127.0.0.1", listener.port(), [2; 20])
+            .await
+            .unwrap();
+        assert_eq!(client.id, PeerId::session().0);
+        let mut accepted = second.recv().await.unwrap();
+        assert_eq!(accepted.id, PeerId::session().0);
+        client.send(Message::Have(3)).await.unwrap();
+        assert_eq!(accepted.recv().await.unwrap(), Message::Have(3));
+
+        connect("::1", listener.port(), [1; 20]).await.unwrap();
+        assert!(first.recv().await.unwrap().address.is_ipv6());
+
+        // Nobody answers for a torrent that isn't registered.
+        assert!(connect("127.0.0.1", listener.port(), [3; 20])
+            .await
+            .is_err());
+        drop(first);
+        assert!(connect("127.0.0.1", listener.port(), [1; 20])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let listener = Listener::bind(0, 1).await.unwrap();
+        let mut incoming = listener.register([1; 20]);
+
+        let client = connect("127.0.0.1", listener.port(), [1; 20])
+            .await
+            .unwrap();
+        let accepted = incoming.recv().await.unwrap();
+        assert!(connect("127.0.0.1", listener.port(), [1; 20])
+            .await
+            .is_err());
+
+        // The slot frees up once the first connection closes.
+        let closed = accepted.closed();
+        drop((client, accepted));
+        closed.await;
+        for _ in 0..50 {
+            if connect("127.0.0.1", listener.port(), [1; 20]).await.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("connection slot was never released");
+    }
+}
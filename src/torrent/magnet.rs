@@ -1,20 +1,27 @@
 use crate::torrent::{
+    announcer::{Announcer, Transfer},
+    parser::{self, Limits},
     peer::Peer,
+    picker::PiecePicker,
+    storage::Storage,
     torrent::Info,
-    tracker::{TrackerRequest, TrackerResponse},
+    tracker::{self, TrackerRequest, TrackerTiers},
 };
-use anyhow::{anyhow, Context, Result};
-use rand::seq::SliceRandom;
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use sha1::{Digest, Sha1};
-use std::{collections::HashMap, net::SocketAddr};
-use tokio::task::JoinSet;
-use url::{form_urlencoded, Url};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio::{sync::Mutex, task::JoinSet};
+use url::Url;
 
-const MAGNET_XT_PREFIX: &'static str = "urn:btih:";
+const MAGNET_XT_PREFIX: &str = "urn:btih:";
 
 pub struct Magnet {
     pub info_hash: [u8; 20], // raw bytes
-    #[allow(unused)]
     pub file_name: Option<String>,
     pub tracker_url: Option<Url>,
 }
@@ -47,18 +54,12 @@ impl Magnet {
 
     pub async fn get_peer_addrs(&self) -> Result<Vec<SocketAddr>> {
         let request = TrackerRequest::new(1);
-        let params = serde_urlencoded::to_string(&request)?;
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash).collect();
-        let url = format!(
-            "{}?{}&info_hash={}",
-            self.tracker_url.as_ref().unwrap(),
-            params,
-            info_hash_str,
-        );
-
-        let response = reqwest::get(url).await?;
+        let url = self
+            .tracker_url
+            .as_ref()
+            .ok_or(anyhow!("magnet link has no tracker"))?;
         let tracker_response =
-            serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
+            tracker::announce(url.as_str(), &self.info_hash, &request, None).await?;
         let peer_addrs = tracker_response.peers();
         println!("Found peers: {:?}", peer_addrs);
         Ok(peer_addrs)
@@ -90,14 +91,16 @@ impl Magnet {
                     let pieces = peer.get_pieces().await?;
                     if pieces.contains(&piece) && peer.supports_extension {
                         peer.extension_handshake().await?;
-                        let metadata = peer.extension_metadata().await?;
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            metadata.piece_length,                               // piece_len
-                            metadata.file_len() - piece * metadata.piece_length, // last piece
-                        );
+                        let metadata = match self.fetch_info(&mut peer).await {
+                            Ok(metadata) => metadata,
+                            Err(e) => {
+                                eprintln!("{} -> {}", peer_address, e);
+                                continue;
+                            }
+                        };
+                        let piece_len = metadata.piece_len(piece)?;
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
@@ -107,58 +110,110 @@ impl Magnet {
         Err(anyhow!("Could not find peer"))
     }
 
-    pub async fn download(&self) -> Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
+    /// Fetches the metadata from the first capable peer, then downloads
+    /// every piece into `output` like [`Torrent::download`](crate::torrent::torrent::Torrent::download).
+    pub async fn download(&self, output: &Path) -> Result<()> {
+        let url = self
+            .tracker_url
+            .as_ref()
+            .ok_or(anyhow!("magnet link has no tracker"))?;
+        let trackers = TrackerTiers::new(vec![vec![url.to_string()]]);
+        // The size is unknown until the metadata arrives; anything but zero
+        // keeps us a leecher in the meantime.
+        let transfer = Transfer::new(1);
+        let (announcer, peer_addrs) = Announcer::start(
+            Arc::new(Mutex::new(trackers)),
+            self.info_hash,
+            transfer.clone(),
+        )
+        .await?;
+        println!("Found peers: {:?}", peer_addrs);
+        let result = tokio::select! {
+            result = self.download_from(peer_addrs, output, &transfer) => result,
+            _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted")),
+        };
+        if result.is_ok() {
+            announcer.completed();
+        }
+        announcer.stop().await?;
+        result
+    }
+
+    /// Fetches the info dictionary from `peer`, checking that it hashes to
+    /// our info hash so a peer can't substitute a layout of its own.
+    async fn fetch_info(&self, peer: &mut Peer) -> Result<Info> {
+        let metadata = peer.extension_metadata().await?;
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        ensure!(
+            info_hash == self.info_hash,
+            "metadata hash {} does not match magnet info hash {}",
+            hex::encode(info_hash),
+            hex::encode(self.info_hash)
+        );
+        parser::from_bytes_limited(&metadata, Limits::default())
+    }
+
+    async fn download_from(
+        &self,
+        peer_addrs: Vec<SocketAddr>,
+        output: &Path,
+        transfer: &Arc<Transfer>,
+    ) -> Result<()> {
         let mut metadata: Option<Info> = None;
-        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
+        let mut peers = Vec::new();
         let mut join_set = JoinSet::new();
 
         for peer_address in peer_addrs {
             match Peer::new(peer_address, self.info_hash).await {
                 Ok(mut peer) => {
                     if peer.supports_extension {
-                        let pieces = peer.get_pieces().await?;
+                        peer.get_pieces().await?;
                         peer.extension_handshake().await?;
                         if metadata.is_none() {
-                            metadata = Some(peer.extension_metadata().await?);
-                        }
-                        for piece in pieces {
-                            peer_piece_map
-                                .entry(piece)
-                                .or_insert_with(Vec::new)
-                                .push(peer.clone());
+                            match self.fetch_info(&mut peer).await {
+                                Ok(info) => metadata = Some(info),
+                                Err(e) => {
+                                    eprintln!("{} -> {}", peer_address, e);
+                                    continue;
+                                }
+                            }
                         }
                         peer.prepare_download().await?;
+                        peers.push(peer);
                     }
                 }
                 Err(e) => eprintln!("{} -> {}", peer_address, e),
             }
         }
 
-        if peer_piece_map.is_empty() || metadata.is_none() {
+        if peers.is_empty() || metadata.is_none() {
             return Err(anyhow!("Could not connect to any peers"));
         }
 
         let metadata = metadata.unwrap();
+        transfer.left.store(metadata.file_len(), Ordering::Relaxed);
         let piece_hashes = metadata.pieces();
         let num_pieces = piece_hashes.len();
-        let piece_len = metadata.piece_length;
-        let file_len = metadata.file_len();
+        let piece_lens = (0..num_pieces)
+            .map(|piece| metadata.piece_len(piece))
+            .collect::<Result<Vec<u32>>>()?;
+        let storage = Arc::new(Storage::new(&metadata, output)?);
+        storage.create()?;
 
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
-        };
-
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
+        let mut picker = PiecePicker::new(num_pieces, metadata.piece_length);
+        let spawn = |join_set: &mut JoinSet<_>, peer: &Peer, piece: usize| {
+            let mut peer = peer.clone();
             let piece_hashes = piece_hashes.clone();
             let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
+            let piece_len = piece_lens[piece];
+            let transfer = transfer.clone();
 
             join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
+                let data = match peer.load_piece(piece as u32, piece_len).await {
                     Ok(data) => {
+                        transfer
+                            .downloaded
+                            .fetch_add(data.len() as u64, Ordering::Relaxed);
                         println!(
                             "Downloaded piece {}/{} from peer {}",
                             piece_number, num_pieces, peer.address
@@ -168,9 +223,9 @@ impl Magnet {
                                 "Piece {}/{} failed verification. Will retry...",
                                 piece_number, num_pieces
                             );
-                            (piece, vec![])
+                            vec![]
                         } else {
-                            (piece, data)
+                            data
                         }
                     }
                     Err(e) => {
@@ -178,28 +233,46 @@ impl Magnet {
                             "Error loading piece {}/{}: {}. Will retry...",
                             piece_number, num_pieces, e
                         );
-                        (piece, vec![])
+                        vec![]
                     }
-                }
+                };
+                (peer.address, piece, data)
             });
         };
+        // Gives `peers` as many pending pieces as they have room for.
+        let fill = |join_set: &mut JoinSet<_>, picker: &mut PiecePicker, peers: &[Peer]| {
+            for peer in peers.iter().filter(|peer| !peer.is_closed()) {
+                for piece in picker.pick(peer.address, |piece| peer.has_piece(piece)) {
+                    spawn(join_set, peer, piece);
+                }
+            }
+        };
 
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
-        }
-
-        let mut file_bytes = vec![0u8; file_len as usize];
+        fill(&mut join_set, &mut picker, &peers);
         while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
+            let (address, piece, data) = join_result.context("Task panicked")?;
             if data.is_empty() {
                 println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
+                picker.finished(address, piece, false);
+                fill(&mut join_set, &mut picker, &peers);
             } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
+                let len = data.len() as u64;
+                storage
+                    .write_async(metadata.piece_offset(piece)?, data)
+                    .await?;
+                transfer.left.fetch_sub(len, Ordering::Relaxed);
+                picker.finished(address, piece, true);
+                let finished: Vec<Peer> = peers
+                    .iter()
+                    .filter(|peer| peer.address == address)
+                    .cloned()
+                    .collect();
+                fill(&mut join_set, &mut picker, &finished);
             }
         }
-        Ok(file_bytes)
+        if let Some(piece) = picker.pending().first() {
+            bail!("no connected peer has piece {}", piece + 1);
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,343 @@
+use anyhow::Result;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Frames longer than this are refused before anything is allocated. A
+/// 16 KiB block is the largest message we ask for; bitfields of very large
+/// torrents are the next biggest.
+pub const MAX_MESSAGE_LEN: u32 = 1 << 21; // 2 MiB
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const EXTENDED: u8 = 20;
+
+/// A peer wire message (BEP 3, plus BEP 5 `port` and BEP 10 `extended`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    /// `id` 0 is the extension handshake; others are as the peer assigned.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Debug, PartialEq, Error)]
+pub enum MessageError {
+    #[error("unknown message id {0}")]
+    UnknownId(u8),
+    #[error("message id {id} cannot carry {len} payload bytes")]
+    InvalidLength { id: u8, len: usize },
+    #[error("message of {0} bytes exceeds the limit")]
+    TooLong(u32),
+}
+
+impl Message {
+    /// The whole frame, length prefix included.
+    pub fn encode(&self) -> Vec<u8> {
+        let (id, payload) = match self {
+            Self::KeepAlive => return 0u32.to_be_bytes().to_vec(),
+            Self::Choke => (CHOKE, vec![]),
+            Self::Unchoke => (UNCHOKE, vec![]),
+            Self::Interested => (INTERESTED, vec![]),
+            Self::NotInterested => (NOT_INTERESTED, vec![]),
+            Self::Have(index) => (HAVE, index.to_be_bytes().to_vec()),
+            Self::Bitfield(bits) => (BITFIELD, bits.clone()),
+            Self::Request {
+                index,
+                begin,
+                length,
+            } => (
+                REQUEST,
+                [*index, *begin, *length].map(u32::to_be_bytes).concat(),
+            ),
+            Self::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = [*index, *begin].map(u32::to_be_bytes).concat();
+                payload.extend_from_slice(block);
+                (PIECE, payload)
+            }
+            Self::Cancel {
+                index,
+                begin,
+                length,
+            } => (
+                CANCEL,
+                [*index, *begin, *length].map(u32::to_be_bytes).concat(),
+            ),
+            Self::Port(port) => (PORT, port.to_be_bytes().to_vec()),
+            Self::Extended { id, payload } => {
+                let mut extended = vec![*id];
+                extended.extend_from_slice(payload);
+                (EXTENDED, extended)
+            }
+        };
+        let mut frame = Vec::with_capacity(5 + payload.len());
+        frame.extend_from_slice(&(1 + payload.len() as u32).to_be_bytes());
+        frame.push(id);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    /// Decodes a frame body, i.e. everything after the length prefix; an
+    /// empty body is a keep-alive.
+    pub fn decode(body: &[u8]) -> Result<Self, MessageError> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Self::KeepAlive);
+        };
+        let invalid = || MessageError::InvalidLength {
+            id,
+            len: payload.len(),
+        };
+        let u32_at = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().unwrap());
+        let expect_len = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(invalid())
+            }
+        };
+
+        let message = match id {
+            CHOKE => expect_len(0).map(|_| Self::Choke)?,
+            UNCHOKE => expect_len(0).map(|_| Self::Unchoke)?,
+            INTERESTED => expect_len(0).map(|_| Self::Interested)?,
+            NOT_INTERESTED => expect_len(0).map(|_| Self::NotInterested)?,
+            HAVE => expect_len(4).map(|_| Self::Have(u32_at(0)))?,
+            BITFIELD => Self::Bitfield(payload.to_vec()),
+            REQUEST | CANCEL => {
+                expect_len(12)?;
+                let (index, begin, length) = (u32_at(0), u32_at(4), u32_at(8));
+                if id == REQUEST {
+                    Self::Request {
+                        index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    Self::Cancel {
+                        index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            PIECE if payload.len() >= 8 => Self::Piece {
+                index: u32_at(0),
+                begin: u32_at(4),
+                block: payload[8..].to_vec(),
+            },
+            PIECE => return Err(invalid()),
+            PORT => {
+                expect_len(2).map(|_| Self::Port(u16::from_be_bytes([payload[0], payload[1]])))?
+            }
+            EXTENDED => {
+                let (&id, payload) = payload.split_first().ok_or_else(invalid)?;
+                Self::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+            _ => return Err(MessageError::UnknownId(id)),
+        };
+        Ok(message)
+    }
+
+    /// Reads the next length-prefixed message, skipping message types we
+    /// don't speak; their frames are consumed whole, so the stream stays in
+    /// sync. Keep-alives are returned, so readers can tell the peer is there.
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        loop {
+            let length = reader.read_u32().await?;
+            if length > MAX_MESSAGE_LEN {
+                return Err(MessageError::TooLong(length).into());
+            }
+
+            let mut body = vec![0u8; length as usize];
+            reader.read_exact(&mut body).await?;
+            match Self::decode(&body) {
+                Err(MessageError::UnknownId(_)) => continue,
+                message => return Ok(message?),
+            }
+        }
+    }
+
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.encode()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_round_trip(message: Message, frame: &[u8]) {
+        assert_eq!(message.encode(), frame, "{:?}", message);
+        assert_eq!(Message::decode(&frame[4..]), Ok(message));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        assert_round_trip(Message::KeepAlive, &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_state_messages() {
+        assert_round_trip(Message::Choke, &[0, 0, 0, 1, 0]);
+        assert_round_trip(Message::Unchoke, &[0, 0, 0, 1, 1]);
+        assert_round_trip(Message::Interested, &[0, 0, 0, 1, 2]);
+        assert_round_trip(Message::NotInterested, &[0, 0, 0, 1, 3]);
+    }
+
+    #[test]
+    fn test_have() {
+        assert_round_trip(Message::Have(0x01020304), &[0, 0, 0, 5, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_bitfield() {
+        assert_round_trip(
+            Message::Bitfield(vec![0b1010_0000, 0xff]),
+            &[0, 0, 0, 3, 5, 0b1010_0000, 0xff],
+        );
+    }
+
+    #[test]
+    fn test_request_and_cancel() {
+        let frame = |id| [0, 0, 0, 13, id, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0];
+        assert_round_trip(
+            Message::Request {
+                index: 1,
+                begin: 0x4000,
+                length: 0x4000,
+            },
+            &frame(6),
+        );
+        assert_round_trip(
+            Message::Cancel {
+                index: 1,
+                begin: 0x4000,
+                length: 0x4000,
+            },
+            &frame(8),
+        );
+    }
+
+    #[test]
+    fn test_piece() {
+        assert_round_trip(
+            Message::Piece {
+                index: 2,
+                begin: 16,
+                block: b"abc".to_vec(),
+            },
+            &[0, 0, 0, 12, 7, 0, 0, 0, 2, 0, 0, 0, 16, b'a', b'b', b'c'],
+        );
+    }
+
+    #[test]
+    fn test_port() {
+        assert_round_trip(Message::Port(6881), &[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+    }
+
+    #[test]
+    fn test_extended() {
+        assert_round_trip(
+            Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+            &[0, 0, 0, 4, 20, 0, b'd', b'e'],
+        );
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert_eq!(Message::decode(&[13]), Err(MessageError::UnknownId(13)));
+        assert_eq!(
+            Message::decode(&[255, 1, 2]),
+            Err(MessageError::UnknownId(255))
+        );
+        for body in [
+            &[0, 0][..],
+            &[4, 0, 0, 1],
+            &[6, 0, 0, 0, 1],
+            &[7, 0, 0, 0, 1, 0, 0, 0],
+            &[9, 1],
+            &[20],
+        ] {
+            assert!(
+                matches!(
+                    Message::decode(body),
+                    Err(MessageError::InvalidLength { .. })
+                ),
+                "{:?}",
+                body
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_frames() {
+        let mut stream = [
+            Message::KeepAlive.encode(),
+            vec![0, 0, 0, 3, 13, 1, 2], // unknown id
+            Message::Have(7).encode(),
+            Message::Unchoke.encode(),
+            (MAX_MESSAGE_LEN + 1).to_be_bytes().to_vec(),
+        ]
+        .concat();
+        let mut reader = stream.as_slice();
+        assert_eq!(
+            Message::read_from(&mut reader).await.unwrap(),
+            Message::KeepAlive
+        );
+        assert_eq!(
+            Message::read_from(&mut reader).await.unwrap(),
+            Message::Have(7)
+        );
+        assert_eq!(
+            Message::read_from(&mut reader).await.unwrap(),
+            Message::Unchoke
+        );
+        assert!(Message::read_from(&mut reader).await.is_err());
+
+        stream.clear();
+        Message::Port(1).write_to(&mut stream).await.unwrap();
+        assert_eq!(stream, Message::Port(1).encode());
+    }
+}
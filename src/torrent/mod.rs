@@ -1,6 +1,23 @@
+pub mod announcer;
+pub mod create;
 pub mod decode;
+pub mod encode;
 pub mod extension;
+pub mod listener;
 pub mod magnet;
+pub mod message;
+pub mod parser;
 pub mod peer;
+pub mod peer_id;
+pub mod picker;
+pub mod pipeline;
+pub mod seed;
+pub mod sign;
+pub mod storage;
+#[cfg(test)]
+pub mod testing;
+#[allow(clippy::module_inception)]
 pub mod torrent;
 pub mod tracker;
+pub mod tracker_server;
+pub mod udp_tracker;
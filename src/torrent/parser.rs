@@ -1,185 +1,574 @@
 use crate::torrent::sign::Sign;
-use anyhow::{bail, Context, Result};
-use serde_json::{Map, Value};
+use serde::de::DeserializeOwned;
+use std::{collections::HashSet, ops::Range};
+use thiserror::Error;
 
-pub fn decode_bencoded_value(encoded_value: &str) -> Result<Value> {
-    Ok(decode_bencoded_start_at(encoded_value, 0)
-        .with_context(|| format!("Unable to call decode_bencoded_start_at"))?
-        .0)
+/// How deeply lists and dictionaries may nest even without [`Limits`]: the
+/// parser, and everything walking its output, recurses once per level.
+pub const MAX_DEPTH: usize = 512;
+
+/// Controls how forgiving the parser is.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Reject encodings that decode fine but are not canonical per BEP 3
+    /// (unsorted or duplicate keys, zero-padded string lengths). Re-encoding
+    /// such input yields different bytes, and so a different info hash.
+    pub strict: bool,
+    /// Resource bounds for input we did not produce ourselves. Nesting is
+    /// capped at [`MAX_DEPTH`] either way.
+    pub limits: Option<Limits>,
 }
 
-fn decode_bencoded_start_at(raw_value: &str, start_index: usize) -> Result<(Value, usize)> {
-    let encoded_value = &raw_value[start_index..];
+/// Upper bounds on what a single bencoded document may contain. Anything
+/// received from a tracker or peer is checked against these before it is
+/// decoded, so hostile input cannot exhaust the stack or memory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// How deeply lists and dictionaries may nest.
+    pub max_depth: usize,
+    /// Longest byte string allowed.
+    pub max_string_len: usize,
+    /// Most entries allowed in any one list or dictionary.
+    pub max_items: usize,
+    /// Size of the whole document.
+    pub max_total_bytes: usize,
+}
 
-    eprintln!("index: {}, encoded_value: {}", start_index, encoded_value);
+impl Default for Limits {
+    /// Generous enough for tracker responses and metadata of very large
+    /// torrents.
+    fn default() -> Self {
+        Self {
+            max_depth: 32,
+            max_string_len: 8 * 1024 * 1024,
+            max_items: 1 << 20,
+            max_total_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
 
-    match encoded_value.chars().next().unwrap() {
-        c if c.is_digit(10) => {
-            // Example: "5:hello" -> "hello
-            let colon_idx = encoded_value.find(":").unwrap();
-            let number_string = &encoded_value[..colon_idx];
-            let number = number_string.parse::<i64>().unwrap();
-            let string = &encoded_value[colon_idx + 1..colon_idx + 1 + number as usize];
+/// A bencoded value borrowing from the input it was parsed from.
+///
+/// Every node remembers the byte range it occupies, so callers can get back
+/// at the exact original encoding of any value (e.g. to hash the `info` dict).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    pub span: Range<usize>,
+    pub value: NodeValue<'a>,
+}
 
-            let part_len = colon_idx + number as usize;
-            Ok((
-                Value::String(string.to_string()),
-                (start_index + part_len + 1),
-            ))
-        }
-        'i' => {
-            let end_index = encoded_value.find("e").unwrap();
-            let number_string = &encoded_value[1..end_index];
-            let number = number_string.parse::<i64>().unwrap();
-            let real_number_str = number.to_string();
-            if real_number_str.len() == number_string.len() {
-                let part_len = number_string.len() + 2;
-                Ok((number.into(), start_index + part_len))
-            } else {
-                bail!("Unhandled encoded value: {}", encoded_value)
-            }
-        }
-        'l' => {
-            let mut list: Vec<Value> = Vec::new();
-            let mut idx = start_index + 1;
+#[derive(Debug, Clone, PartialEq)]
+pub enum NodeValue<'a> {
+    Bytes(&'a [u8]),
+    Int(i64),
+    List(Vec<Node<'a>>),
+    /// Entries in the order they appear in the input.
+    Dict(Vec<(&'a [u8], Node<'a>)>),
+}
 
-            while raw_value.chars().nth(idx).unwrap() != 'e' {
-                let (value, new_idx) = decode_bencoded_start_at(raw_value, idx)
-                    .with_context(|| format!("Unable to decode bencoded start at"))?;
+/// Errors are reported with the byte offset in the input where the problem
+/// was detected.
+#[derive(Debug, Error, PartialEq)]
+pub enum BencodeError {
+    #[error("unexpected end of input at byte {offset}")]
+    UnexpectedEof { offset: usize },
+    #[error("unexpected byte {byte:#04x} at byte {offset}")]
+    UnexpectedByte { byte: u8, offset: usize },
+    #[error("invalid integer at byte {offset}")]
+    InvalidInteger { offset: usize },
+    #[error("invalid string length at byte {offset}")]
+    InvalidLength { offset: usize },
+    #[error("number with leading zero at byte {offset}")]
+    LeadingZero { offset: usize },
+    #[error("dictionary key at byte {offset} is not a string")]
+    NonStringKey { offset: usize },
+    #[error("trailing data at byte {offset}")]
+    TrailingData { offset: usize },
+    #[error(transparent)]
+    NonCanonical(#[from] NonCanonical),
+    #[error("{limit} exceeded at byte {offset}")]
+    LimitExceeded { limit: Limit, offset: usize },
+}
 
-                list.push(value);
-                idx = new_idx;
+#[derive(Debug, Clone, Copy, Error, PartialEq)]
+pub enum Limit {
+    #[error("maximum nesting depth")]
+    Depth,
+    #[error("maximum string length")]
+    StringLength,
+    #[error("maximum number of items")]
+    Items,
+    #[error("maximum input size")]
+    TotalBytes,
+}
 
-                if raw_value.len() <= idx {
-                    bail!("Unhandled encoded value: {}", encoded_value)
-                }
-            }
-            Ok((list.into(), (idx + 1)))
+/// A spot where the input is valid bencode but not in canonical form.
+#[derive(Debug, Clone, Error, PartialEq)]
+#[error("{kind} at byte {offset}")]
+pub struct NonCanonical {
+    pub kind: NonCanonicalKind,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, Copy, Error, PartialEq)]
+pub enum NonCanonicalKind {
+    #[error("unsorted dictionary key")]
+    UnsortedKey,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("string length with leading zero")]
+    LengthLeadingZero,
+}
+
+impl<'a> Node<'a> {
+    /// Looks up `key` if this node is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&Node<'a>> {
+        match &self.value {
+            NodeValue::Dict(entries) => entries.iter().find(|(k, _)| *k == key).map(|(_, v)| v),
+            _ => None,
         }
-        'd' => {
-            let mut dictionary: Map<String, Value> = Map::new();
-            let mut idx = start_index + 1;
+    }
 
-            while raw_value.chars().nth(idx).unwrap() != 'e' {
-                let (key, new_idx) = decode_bencoded_start_at(raw_value, idx)
-                    .with_context(|| format!("Unable to decode bencoded start at"))?;
+    /// Returns the raw encoding of this node within `input`.
+    pub fn raw<'b>(&self, input: &'b [u8]) -> &'b [u8] {
+        &input[self.span.clone()]
+    }
+}
 
-                let (value, new_idx) = decode_bencoded_start_at(raw_value, new_idx)
-                    .with_context(|| format!("Unable to decode bencoded start at"))?;
+/// Parses `input` as exactly one bencoded value.
+pub fn parse(input: &[u8]) -> Result<Node<'_>, BencodeError> {
+    parse_with(input, &ParseOptions::default())
+}
 
-                if let Value::String(key) = key {
-                    dictionary.insert(key, value);
-                } else {
-                    bail!("Unhandled encoded value: {}", encoded_value)
-                }
+pub fn parse_with<'a>(input: &'a [u8], options: &ParseOptions) -> Result<Node<'a>, BencodeError> {
+    Parser::new(input, options).parse_all()
+}
 
-                idx = new_idx;
-                if raw_value.len() <= idx {
-                    bail!("Unhandled encoded value: {}", encoded_value)
-                }
-            }
+/// Parses the bencoded value at the start of `input`. Any bytes after it are
+/// left alone; the end of the returned node's span marks where it stopped.
+pub fn parse_prefix_with<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> Result<Node<'a>, BencodeError> {
+    Parser::new(input, options).parse_root()
+}
+
+/// Deserializes untrusted input into `T` after checking it against `limits`.
+pub fn from_bytes_limited<T: DeserializeOwned>(input: &[u8], limits: Limits) -> anyhow::Result<T> {
+    let options = ParseOptions {
+        limits: Some(limits),
+        ..Default::default()
+    };
+    parse_with(input, &options)?;
+    Ok(serde_bencode::from_bytes(input)?)
+}
+
+/// Parses `input` leniently and returns every non-canonical encoding found,
+/// in input order. Hard syntax errors are still returned as errors.
+pub fn validate(input: &[u8]) -> Result<Vec<NonCanonical>, BencodeError> {
+    let mut parser = Parser::new(input, &ParseOptions::default());
+    parser.parse_all()?;
+    Ok(parser.violations)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+    strict: bool,
+    limits: Option<Limits>,
+    violations: Vec<NonCanonical>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a [u8], options: &ParseOptions) -> Self {
+        Self {
+            input,
+            pos: 0,
+            depth: 0,
+            strict: options.strict,
+            limits: options.limits,
+            violations: Vec::new(),
+        }
+    }
 
-            Ok((dictionary.into(), (idx + 1)))
+    /// Fails if `value` is over the configured bound for `limit`.
+    fn check_limit(&self, limit: Limit, value: usize, offset: usize) -> Result<(), BencodeError> {
+        let Some(limits) = &self.limits else {
+            return Ok(());
+        };
+        let max = match limit {
+            Limit::Depth => limits.max_depth,
+            Limit::StringLength => limits.max_string_len,
+            Limit::Items => limits.max_items,
+            Limit::TotalBytes => limits.max_total_bytes,
+        };
+        if value > max {
+            return Err(BencodeError::LimitExceeded { limit, offset });
         }
-        _ => {
-            bail!("Unhandled encoded value: {}", encoded_value)
+        Ok(())
+    }
+
+    fn parse_root(&mut self) -> Result<Node<'a>, BencodeError> {
+        self.check_limit(Limit::TotalBytes, self.input.len(), 0)?;
+        self.parse_value()
+    }
+
+    fn parse_all(&mut self) -> Result<Node<'a>, BencodeError> {
+        let node = self.parse_root()?;
+        if node.span.end != self.input.len() {
+            return Err(BencodeError::TrailingData {
+                offset: node.span.end,
+            });
         }
+        Ok(node)
     }
-}
 
-pub fn decode_bencoded_vec(encoded_vec: &Vec<u8>) -> Result<Value> {
-    Ok(decode_bencoded_vec_start_at(&encoded_vec, 0)
-        .with_context(|| format!("Unable to call decode_bencoded_vec_start_at function"))?
-        .0)
-}
-
-pub fn decode_bencoded_vec_start_at(raw_vec: &[u8], start_index: usize) -> Result<(Value, usize)> {
-    let encoded_vec = &raw_vec[start_index..];
-
-    match encoded_vec.iter().next().unwrap() {
-        &n if 48 <= n && n <= 57 => {
-            let colon_index = encoded_vec.iter().position(|&x| x == Sign::COLON).unwrap();
-            match read_vec_u8_to_string(&encoded_vec[0..colon_index]) {
-                Some(number_string) => {
-                    let number = number_string.parse::<i64>().unwrap();
-                    let read_result: Option<String> = read_vec_u8_to_string(
-                        &encoded_vec[colon_index + 1..colon_index + 1 + number as usize],
-                    );
-                    if let Some(string) = read_result {
-                        let part_len = colon_index + number as usize;
-                        Ok((Value::String(string), (start_index + part_len + 1)))
-                    } else {
-                        Ok((
-                            encoded_vec[colon_index + 1..colon_index + 1 + number as usize]
-                                .to_vec()
-                                .into(),
-                            (start_index + colon_index + number as usize + 1),
-                        ))
-                    }
+    /// Fails in strict mode, otherwise records the violation and carries on.
+    fn non_canonical(&mut self, kind: NonCanonicalKind, offset: usize) -> Result<(), BencodeError> {
+        let violation = NonCanonical { kind, offset };
+        if self.strict {
+            return Err(violation.into());
+        }
+        self.violations.push(violation);
+        Ok(())
+    }
+
+    fn peek(&self) -> Result<u8, BencodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or(BencodeError::UnexpectedEof { offset: self.pos })
+    }
+
+    fn parse_value(&mut self) -> Result<Node<'a>, BencodeError> {
+        let start = self.pos;
+        let value = match self.peek()? {
+            b'0'..=b'9' => NodeValue::Bytes(self.parse_bytes()?),
+            Sign::I => NodeValue::Int(self.parse_int()?),
+            Sign::L => {
+                self.enter(start)?;
+                let mut list = Vec::new();
+                while self.peek()? != Sign::E {
+                    self.check_limit(Limit::Items, list.len() + 1, self.pos)?;
+                    list.push(self.parse_value()?);
                 }
-                _ => {
-                    bail!(
-                        "Can not read length of the string at index: {}",
-                        start_index
-                    );
+                self.leave();
+                NodeValue::List(list)
+            }
+            Sign::D => {
+                self.enter(start)?;
+                let mut dict: Vec<(&[u8], Node)> = Vec::new();
+                let mut seen = HashSet::new();
+                while self.peek()? != Sign::E {
+                    let key_start = self.pos;
+                    self.check_limit(Limit::Items, dict.len() + 1, key_start)?;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(BencodeError::NonStringKey { offset: key_start });
+                    }
+                    let key = self.parse_bytes()?;
+                    if let Some(&(prev, _)) = dict.last() {
+                        // A key that repeats an earlier one is reported as a
+                        // duplicate even when it is also out of order.
+                        if key <= prev {
+                            let kind = if seen.contains(key) {
+                                NonCanonicalKind::DuplicateKey
+                            } else {
+                                NonCanonicalKind::UnsortedKey
+                            };
+                            self.non_canonical(kind, key_start)?;
+                        }
+                    }
+                    seen.insert(key);
+                    let value = self.parse_value()?;
+                    dict.push((key, value));
                 }
+                self.leave();
+                NodeValue::Dict(dict)
             }
-        }
-        &Sign::I => {
-            let end_index = encoded_vec.iter().position(|&x| x == Sign::E).unwrap();
-            let number_string = read_vec_u8_to_string(&encoded_vec[1..end_index]).unwrap();
-            let number = number_string.parse::<i64>().unwrap();
-            let real_number_str = number.to_string();
-
-            if real_number_str.len() == number_string.len() {
-                let part_len = number_string.len() + 2;
-                Ok((number.into(), (start_index + part_len)))
-            } else {
-                bail!("Unhandled encoded value at index: {}", start_index);
+            byte => {
+                return Err(BencodeError::UnexpectedByte {
+                    byte,
+                    offset: start,
+                })
             }
-        }
-        &Sign::L => {
-            let mut list: Vec<Value> = Vec::new();
-            let mut index = start_index + 1;
+        };
 
-            while raw_vec.len() > index && raw_vec[index] != Sign::E {
-                let (value, new_index) = decode_bencoded_vec_start_at(&raw_vec, index)
-                    .with_context(|| format!("Unable to bencode vector from start index"))?;
+        Ok(Node {
+            span: start..self.pos,
+            value,
+        })
+    }
 
-                list.push(value);
-                index = new_index;
-            }
-            Ok((list.into(), (index + 1)))
+    /// Steps into the list or dict starting at `start`.
+    fn enter(&mut self, start: usize) -> Result<(), BencodeError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(BencodeError::LimitExceeded {
+                limit: Limit::Depth,
+                offset: start,
+            });
         }
-        &Sign::D => {
-            let mut dict: Map<String, Value> = Map::new();
-            let mut index = start_index + 1;
+        self.check_limit(Limit::Depth, self.depth, start)?;
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Steps over the `e` closing the current list or dict.
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
 
-            while raw_vec.len() > index && raw_vec[index] != Sign::E {
-                let (key, new_index) = decode_bencoded_vec_start_at(raw_vec, index)
-                    .with_context(|| format!("Unable to bencode vector from start index"))?;
+    /// Returns the bytes between the current position and the next
+    /// `terminator`, leaving the position just past the terminator.
+    fn read_until(&mut self, terminator: u8) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let len = self.input[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or(BencodeError::UnexpectedEof {
+                offset: self.input.len(),
+            })?;
+        self.pos = start + len + 1;
+        Ok(&self.input[start..start + len])
+    }
 
-                let (value, new_index) = decode_bencoded_vec_start_at(raw_vec, new_index)
-                    .with_context(|| format!("Unable to bencode vector from start index"))?;
+    fn parse_bytes(&mut self) -> Result<&'a [u8], BencodeError> {
+        let start = self.pos;
+        let digits = self.read_until(Sign::COLON)?;
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return Err(BencodeError::InvalidLength { offset: start });
+        }
+        if digits.len() > 1 && digits[0] == b'0' {
+            self.non_canonical(NonCanonicalKind::LengthLeadingZero, start)?;
+        }
+        let length = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(BencodeError::InvalidLength { offset: start })?;
+        self.check_limit(Limit::StringLength, length, start)?;
 
-                if let Value::String(key) = key {
-                    dict.insert(key, value);
-                } else {
-                    bail!("Unhandled encoded value at: {}", start_index)
-                }
-                index = new_index;
-            }
-            Ok((dict.into(), (index + 1)))
+        let data_start = self.pos;
+        let data_end = data_start
+            .checked_add(length)
+            .filter(|&end| end <= self.input.len())
+            .ok_or(BencodeError::UnexpectedEof {
+                offset: self.input.len(),
+            })?;
+        self.pos = data_end;
+        Ok(&self.input[data_start..data_end])
+    }
+
+    fn parse_int(&mut self) -> Result<i64, BencodeError> {
+        let start = self.pos;
+        self.pos += 1;
+        let number = self.read_until(Sign::E)?;
+        let digits = number.strip_prefix(b"-").unwrap_or(number);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return Err(BencodeError::InvalidInteger { offset: start });
+        }
+        if digits.len() > 1 && digits[0] == b'0' {
+            return Err(BencodeError::LeadingZero { offset: start });
         }
-        _ => {
-            bail!("Unhandled encoded value at: {}", start_index)
+        if number == b"-0" {
+            return Err(BencodeError::InvalidInteger { offset: start });
         }
+        std::str::from_utf8(number)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(BencodeError::InvalidInteger { offset: start })
     }
 }
 
-fn read_vec_u8_to_string(vec: &[u8]) -> Option<String> {
-    match String::from_utf8(vec.to_vec()) {
-        Ok(result) => Some(result),
-        Err(_) => None,
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_records_spans() {
+        let input = b"d4:infod6:lengthi3ee4:listl1:ai-2eee";
+        let root = parse(input).unwrap();
+        assert_eq!(root.span, 0..input.len());
+
+        let info = root.get(b"info").unwrap();
+        assert_eq!(info.raw(input), b"d6:lengthi3ee");
+        assert_eq!(info.get(b"length").unwrap().value, NodeValue::Int(3));
+
+        let list = root.get(b"list").unwrap();
+        assert_eq!(list.raw(input), b"l1:ai-2ee");
+    }
+
+    #[test]
+    fn test_parse_prefix_stops_after_first_value() {
+        let input = b"d8:msg_typei1ee<raw metadata>";
+        let root = parse_prefix_with(input, &ParseOptions::default()).unwrap();
+        assert_eq!(&input[root.span.end..], b"<raw metadata>");
+        assert_eq!(parse(input), Err(BencodeError::TrailingData { offset: 15 }));
+    }
+
+    #[test]
+    fn test_parse_truncated_input() {
+        for (input, offset) in [
+            (b"".as_slice(), 0),
+            (b"l", 1),
+            (b"d3:foo", 6),
+            (b"5:abc", 5),
+            (b"i42", 3),
+            (b"12", 2),
+        ] {
+            assert_eq!(
+                parse(input),
+                Err(BencodeError::UnexpectedEof { offset }),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_integers() {
+        assert_eq!(parse(b"i-42e").unwrap().value, NodeValue::Int(-42));
+        assert_eq!(parse(b"i0e").unwrap().value, NodeValue::Int(0));
+        assert_eq!(
+            parse(b"l1:ai03ee"),
+            Err(BencodeError::LeadingZero { offset: 4 })
+        );
+        for input in [
+            b"ie".as_slice(),
+            b"i-e",
+            b"i-0e",
+            b"i1x2e",
+            b"i99999999999999999999e",
+        ] {
+            assert_eq!(
+                parse(input),
+                Err(BencodeError::InvalidInteger { offset: 0 })
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_structure() {
+        assert_eq!(
+            parse(b"di1e1:ae"),
+            Err(BencodeError::NonStringKey { offset: 1 })
+        );
+        assert_eq!(
+            parse(b"l1:ax"),
+            Err(BencodeError::UnexpectedByte {
+                byte: b'x',
+                offset: 4
+            })
+        );
+        assert_eq!(
+            parse(b"1a:x"),
+            Err(BencodeError::InvalidLength { offset: 0 })
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_non_canonical_encodings() {
+        let input = b"d1:bi1e1:ai2e1:ai3e03:keyi4ee";
+        let violations = validate(input).unwrap();
+        assert_eq!(
+            violations,
+            vec![
+                NonCanonical {
+                    kind: NonCanonicalKind::UnsortedKey,
+                    offset: 7
+                },
+                NonCanonical {
+                    kind: NonCanonicalKind::DuplicateKey,
+                    offset: 13
+                },
+                NonCanonical {
+                    kind: NonCanonicalKind::LengthLeadingZero,
+                    offset: 19
+                },
+            ]
+        );
+        assert!(validate(b"d1:ai1e1:bl2:xyee").unwrap().is_empty());
+        assert_eq!(
+            validate(b"d1:ai0e1:bi0e1:ai0ee").unwrap(),
+            vec![NonCanonical {
+                kind: NonCanonicalKind::DuplicateKey,
+                offset: 13
+            }]
+        );
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_non_canonical_input() {
+        let strict = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(parse_with(b"d1:ai1e1:bi2ee", &strict).is_ok());
+        assert_eq!(
+            parse_with(b"d1:bi1e1:ai2ee", &strict),
+            Err(BencodeError::NonCanonical(NonCanonical {
+                kind: NonCanonicalKind::UnsortedKey,
+                offset: 7
+            }))
+        );
+        assert_eq!(
+            parse_with(b"d1:ai1e1:ai2ee", &strict)
+                .unwrap_err()
+                .to_string(),
+            "duplicate dictionary key at byte 7"
+        );
+        assert!(parse(b"d1:ai1e1:ai2ee").is_ok());
+    }
+
+    #[test]
+    fn test_limits_reject_oversized_input() {
+        let limits = Limits {
+            max_depth: 2,
+            max_string_len: 4,
+            max_items: 3,
+            max_total_bytes: 32,
+        };
+        let options = ParseOptions {
+            limits: Some(limits),
+            ..Default::default()
+        };
+        let exceeded = |input: &[u8]| match parse_with(input, &options) {
+            Err(BencodeError::LimitExceeded { limit, offset }) => Some((limit, offset)),
+            _ => None,
+        };
+
+        assert!(parse_with(b"d1:al4:abcdee", &options).is_ok());
+        assert_eq!(exceeded(b"lllee"), Some((Limit::Depth, 2)));
+        assert_eq!(exceeded(b"l5:abcdee"), Some((Limit::StringLength, 1)));
+        assert_eq!(exceeded(b"li1ei2ei3ei4ee"), Some((Limit::Items, 10)));
+        assert_eq!(exceeded(&[b'l'; 64]), Some((Limit::TotalBytes, 0)));
+        // Unbounded by default.
+        assert!(parse(&[[b'l'; 64], [b'e'; 64]].concat()).is_ok());
+    }
+
+    #[test]
+    fn test_deep_nesting_is_an_error() {
+        let nested = |depth| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse(&nested(20_000)),
+            Err(BencodeError::LimitExceeded {
+                limit: Limit::Depth,
+                offset: MAX_DEPTH,
+            })
+        );
+        assert!(validate(&nested(20_000)).is_err());
+    }
+
+    #[test]
+    fn test_from_bytes_limited() {
+        let limits = Limits {
+            max_depth: 1,
+            ..Default::default()
+        };
+        let list: Vec<i64> = from_bytes_limited(b"li1ei2ee", limits).unwrap();
+        assert_eq!(list, vec![1, 2]);
+        assert!(from_bytes_limited::<Vec<Vec<i64>>>(b"lli1eee", limits).is_err());
     }
 }
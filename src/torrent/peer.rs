@@ -1,21 +1,47 @@
 use crate::torrent::{
     extension::{ExtensionHeader, ExtensionMessage, ExtensionMessageType},
-    torrent::Info,
+    message::{Message, MAX_MESSAGE_LEN},
+    parser::{self, Limits, ParseOptions},
+    peer_id::PeerId,
+    pipeline::Pipeline,
 };
-use anyhow::{ensure, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use bitvec::prelude::*;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
-use std::{mem, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    sync::Mutex,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, watch, Mutex,
+    },
     task::JoinSet,
+    time::timeout,
 };
 
-const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB
+pub const BLOCK_SIZE: u32 = 16 * 1024; // 16 KiB
 const EXTENSION_SUPPORT_FLAG: u64 = 1 << 20;
+/// Messages held for [`Peer::recv`], enough for a full queue of requests
+/// from a leecher. Beyond that nobody is reading, and the rest are dropped.
+const INCOMING_CAPACITY: usize = 512;
+/// Messages queued for the writer before [`Peer::send`] waits, so a peer
+/// that stops reading stops us producing blocks for it.
+const OUTGOING_CAPACITY: usize = 64;
+/// Longest we stay quiet before sending a keep-alive.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+/// A peer silent for this long is gone, even if its socket isn't; it
+/// leaves room for the keep-alives of peers that are merely quiet.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
 
 #[derive(Serialize, Deserialize)]
 pub struct Handshake {
@@ -30,13 +56,12 @@ impl Handshake {
     pub fn new(info_hash: [u8; 20]) -> Self {
         let mut reserved = 0;
         reserved |= EXTENSION_SUPPORT_FLAG;
-        let peer_id: [u8; 20] = Peer::gen_peer_id().as_bytes().try_into().unwrap();
         Self {
             length: 19,
             protocol: *b"BitTorrent protocol",
             reserved: reserved.to_be_bytes(),
             info_hash,
-            peer_id,
+            peer_id: PeerId::session().0,
         }
     }
 
@@ -45,13 +70,31 @@ impl Handshake {
     }
 }
 
+/// A block request, `(index, begin, length)`; a `PIECE` answers the request
+/// with the same key.
+type BlockKey = (u32, u32, u32);
+
+type PendingBlocks = Arc<StdMutex<HashMap<BlockKey, oneshot::Sender<Vec<u8>>>>>;
+
+/// A connection to one peer. A reader task and a writer task own the two
+/// halves of the stream, so any number of clones can have requests in
+/// flight at once: `PIECE` replies are handed to whichever request they
+/// answer, choke state and the pieces the peer has are tracked, and
+/// everything else is queued for [`Peer::recv`] as long as there is room.
 #[derive(Clone)]
 pub struct Peer {
     pub address: SocketAddr,
     pub id: [u8; 20],
-    pub stream: Arc<Mutex<TcpStream>>,
     pub supports_extension: bool,
     pub metadata_extension_id: Option<u8>,
+    outgoing: mpsc::Sender<Message>,
+    incoming: Arc<Mutex<mpsc::Receiver<Message>>>,
+    pending: PendingBlocks,
+    unchoked: watch::Receiver<bool>,
+    /// The pieces the peer has, known once it opens: from its `BITFIELD`, or
+    /// none if it opened with anything else. Each `HAVE` adds one.
+    have: watch::Receiver<Option<BitVec<u8, Msb0>>>,
+    pipeline: Arc<Pipeline>,
 }
 
 impl Peer {
@@ -72,92 +115,176 @@ impl Peer {
             .context("failed to receive handshake")?;
 
         handshake = bincode::deserialize(&handshake_bytes)?;
-        let peer = Peer {
+        Ok(Self::from_stream(address, &handshake, peer_stream))
+    }
+
+    /// Starts the reader and writer tasks on a stream whose handshake is
+    /// done; `handshake` is the one the peer sent.
+    pub fn from_stream(address: SocketAddr, handshake: &Handshake, stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_CAPACITY);
+        let (incoming_tx, incoming) = mpsc::channel(INCOMING_CAPACITY);
+        let (unchoked_tx, unchoked) = watch::channel(false);
+        let (have_tx, have) = watch::channel(None);
+        let pending = PendingBlocks::default();
+
+        tokio::spawn(write_loop(writer, outgoing_rx));
+        let reader_pending = pending.clone();
+        tokio::spawn(async move {
+            let _ = read_loop(reader, &reader_pending, incoming_tx, &unchoked_tx, have_tx).await;
+            // Nothing is answered from here on: new requests see a choke, and
+            // dropping the senders fails every request still waiting.
+            unchoked_tx.send_replace(false);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        Self {
             address,
             id: handshake.peer_id,
-            stream: Arc::new(Mutex::new(peer_stream)),
             supports_extension: handshake.supports_extension(),
             metadata_extension_id: None,
-        };
-        Ok(peer)
+            outgoing,
+            incoming: Arc::new(Mutex::new(incoming)),
+            pending,
+            unchoked,
+            have,
+            pipeline: Arc::new(Pipeline::new()),
+        }
     }
 
     pub async fn extension_handshake(&mut self) -> Result<()> {
         let ext_header = ExtensionHeader::new();
-        let mut payload = serde_bencode::to_bytes(&ext_header)?;
-        payload.insert(0, 0);
+        let payload = serde_bencode::to_bytes(&ext_header)?;
 
-        let handshake = Message::new(MessageId::EXTENSION, payload);
+        let handshake = Message::Extended { id: 0, payload };
         self.send(handshake).await?;
-        let reply = self.recv().await?;
-        let ext_header = serde_bencode::from_bytes::<ExtensionHeader>(&reply.payload[1..])?;
+        let Message::Extended { payload, .. } = self.recv().await? else {
+            bail!("expected extension handshake");
+        };
+        let ext_header =
+            parser::from_bytes_limited::<ExtensionHeader>(&payload, Limits::default())?;
         self.metadata_extension_id = Some(ext_header.m.ut_metadata);
+        if let Some(reqq) = ext_header.reqq {
+            self.pipeline.set_reqq(reqq as usize);
+        }
         Ok(())
     }
 
-    pub async fn extension_metadata(&mut self) -> Result<Info> {
+    /// Fetches the raw bencoded `info` dict from the peer.
+    pub async fn extension_metadata(&mut self) -> Result<Vec<u8>> {
         let ext_msg = ExtensionMessage {
             msg_type: ExtensionMessageType::Request,
             piece: 0,
             total_size: None,
         };
-        let mut payload = serde_bencode::to_bytes(&ext_msg)?;
-        let extension_msg_id = self
+        let payload = serde_bencode::to_bytes(&ext_msg)?;
+        let id = self
             .metadata_extension_id
             .expect("metadata extension id should be set during handshake");
-        payload.insert(0, extension_msg_id);
-
-        let msg = Message::new(MessageId::EXTENSION, payload);
-        self.send(msg).await?;
-        let reply = self.recv().await?;
-        let ext_msg = serde_bencode::from_bytes::<ExtensionMessage>(&reply.payload[1..])?;
-        let metadata_piece_len = ext_msg.total_size.unwrap();
-        let metadata = &reply.payload[reply.payload.len() - metadata_piece_len as usize..];
-        let torrent_info = serde_bencode::from_bytes::<Info>(metadata)?;
-        Ok(torrent_info)
-    }
-
-    async fn recv(&mut self) -> Result<Message> {
-        let mut stream = self.stream.lock().await;
-        let mut buf = [0u8; 4];
-        stream.read_exact(&mut buf).await?;
-        let length = u32::from_be_bytes(buf);
-
-        let mut buf = [0u8; 1];
-        stream.read_exact(&mut buf).await?;
-        let id: MessageId = unsafe { mem::transmute(buf[0]) };
-
-        let mut buf = vec![0u8; length as usize - mem::size_of::<MessageId>()];
-        stream.read_exact(&mut buf).await?;
-        Ok(Message {
-            length,
-            id,
-            payload: buf,
-        })
+
+        self.send(Message::Extended { id, payload }).await?;
+        let Message::Extended {
+            payload: ext_payload,
+            ..
+        } = self.recv().await?
+        else {
+            bail!("expected metadata message");
+        };
+        // The metadata piece follows the bencoded message dict directly.
+        let ext_payload = ext_payload.as_slice();
+        let options = ParseOptions {
+            limits: Some(Limits::default()),
+            ..Default::default()
+        };
+        let ext_node = parser::parse_prefix_with(ext_payload, &options)?;
+        let ext_msg = serde_bencode::from_bytes::<ExtensionMessage>(ext_node.raw(ext_payload))?;
+        let metadata = &ext_payload[ext_node.span.end..];
+        ensure!(
+            ext_msg.total_size == Some(metadata.len() as u32),
+            "expected {:?} bytes of metadata, got {}",
+            ext_msg.total_size,
+            metadata.len()
+        );
+        Ok(metadata.to_vec())
     }
 
-    async fn send(&mut self, msg: Message) -> Result<()> {
-        let mut stream = self.stream.lock().await;
-        stream.write_all(&msg.as_bytes()).await?;
-        Ok(())
+    /// Whether the connection is gone.
+    pub fn is_closed(&self) -> bool {
+        self.unchoked.has_changed().is_err()
+    }
+
+    /// Resolves once the connection is gone; holds nothing that keeps it
+    /// open.
+    pub fn closed(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut unchoked = self.unchoked.clone();
+        async move { while unchoked.changed().await.is_ok() {} }
+    }
+
+    /// The next message that isn't a `PIECE`, `CHOKE` or `UNCHOKE`; those
+    /// are dealt with by the reader task.
+    pub async fn recv(&mut self) -> Result<Message> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .context("peer disconnected")
     }
 
+    /// Queues `msg`, waiting while the writer is [`OUTGOING_CAPACITY`]
+    /// messages behind.
+    pub async fn send(&self, msg: Message) -> Result<()> {
+        self.outgoing
+            .send(msg)
+            .await
+            .map_err(|_| anyhow!("peer disconnected"))
+    }
+
+    /// Queues `msg` without waiting. A peer too far behind to take it misses
+    /// it; only a closed connection is an error.
+    pub fn try_send(&self, msg: Message) -> Result<()> {
+        match self.outgoing.try_send(msg) {
+            Err(TrySendError::Closed(_)) => bail!("peer disconnected"),
+            _ => Ok(()),
+        }
+    }
+
+    /// The pieces the peer has, once it has opened with its `BITFIELD` or
+    /// anything else, as peers without pieces may.
     pub async fn get_pieces(&mut self) -> Result<Vec<usize>> {
-        let msg = self.recv().await?;
-        ensure!(msg.id == MessageId::BITFIELD);
-        let bitfield = BitVec::<u8, Msb0>::from_vec(msg.payload);
-        let pieces = bitfield.iter_ones().collect();
+        let have = self
+            .have
+            .wait_for(Option::is_some)
+            .await
+            .context("peer disconnected")?;
+        let pieces = have.iter().flat_map(|have| have.iter_ones()).collect();
         Ok(pieces)
     }
 
+    /// Whether the peer has said it has `piece`, so far.
+    pub fn has_piece(&self, piece: usize) -> bool {
+        self.have
+            .borrow()
+            .as_ref()
+            .and_then(|have| have.get(piece).as_deref().copied())
+            .unwrap_or(false)
+    }
+
+    /// Tells the peer we're interested, unless it already unchoked us, and
+    /// waits until it does.
     pub async fn prepare_download(&mut self) -> Result<()> {
-        let interested = Message::new(MessageId::INTERESTED, vec![]);
-        self.send(interested).await?;
-        let msg = self.recv().await?;
-        ensure!(msg.id == MessageId::UNCHOKE);
+        if !*self.unchoked.borrow() {
+            self.send(Message::Interested).await?;
+        }
+        self.unchoked
+            .wait_for(|unchoked| *unchoked)
+            .await
+            .context("peer disconnected")?;
         Ok(())
     }
 
+    /// Fetches a whole piece, retrying blocks that were choked away. Fails
+    /// once the connection is gone, so another peer can be tried.
     pub async fn load_piece(&mut self, index: u32, piece_len: u32) -> Result<Vec<u8>> {
         let mut piece = vec![0u8; piece_len as usize];
         let mut join_set = JoinSet::new();
@@ -166,7 +293,7 @@ impl Peer {
             let length = BLOCK_SIZE.min(piece_len - offset);
             join_set.spawn(async move {
                 match peer.load_block(index, offset, length).await {
-                    Ok(msg) => (offset, msg.payload[8..].to_vec()),
+                    Ok(block) => (offset, block),
                     Err(err) => {
                         eprintln!("Error loading block: {}. Will retry...", err);
                         (offset, vec![])
@@ -182,6 +309,7 @@ impl Peer {
         while let Some(join_result) = join_set.join_next().await {
             let (offset, data) = join_result.context("Task panicked")?;
             if data.is_empty() {
+                ensure!(!self.is_closed(), "peer disconnected");
                 spawn(&mut join_set, self.clone(), offset);
             } else {
                 let start = offset as usize;
@@ -193,61 +321,318 @@ impl Peer {
         Ok(piece)
     }
 
-    async fn load_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Message> {
-        let payload = vec![
-            index.to_be_bytes(),
-            begin.to_be_bytes(),
-            length.to_be_bytes(),
-        ]
-        .concat();
-        let request = Message::new(MessageId::REQUEST, payload);
-        self.send(request).await?;
-        let msg = self.recv().await?;
-        ensure!(msg.id == MessageId::PIECE);
-        Ok(msg)
-    }
+    /// Requests one block once the pipeline has room for it.
+    async fn load_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let slot = self.pipeline.acquire().await;
+        self.unchoked
+            .wait_for(|unchoked| *unchoked)
+            .await
+            .context("peer disconnected")?;
+        let key = (index, begin, length);
+        let (sender, block) = oneshot::channel();
+        self.pending.lock().unwrap().insert(key, sender);
+        // A choke between the wait and the insert would leave the request
+        // unanswered forever.
+        if !*self.unchoked.borrow() {
+            self.pending.lock().unwrap().remove(&key);
+            bail!("peer choked us");
+        }
 
-    pub fn gen_peer_id() -> String {
-        let peer_id_len = 20;
-        (0..peer_id_len)
-            .map(|_| rand::thread_rng().gen_range(0..10).to_string())
-            .collect()
+        self.send(Message::Request {
+            index,
+            begin,
+            length,
+        })
+        .await?;
+        let block = block
+            .await
+            .context("request dropped: peer choked us or disconnected")?;
+        slot.completed(block.len());
+        Ok(block)
     }
 }
 
-#[derive(Debug)]
-struct Message {
-    length: u32,
-    id: MessageId,
-    payload: Vec<u8>,
+async fn write_loop(mut writer: OwnedWriteHalf, mut outgoing: mpsc::Receiver<Message>) {
+    loop {
+        let msg = match timeout(KEEP_ALIVE_INTERVAL, outgoing.recv()).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(_) => Message::KeepAlive,
+        };
+        if msg.write_to(&mut writer).await.is_err() {
+            break;
+        }
+    }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-#[repr(u8)]
-enum MessageId {
-    BITFIELD = 5,
-    INTERESTED = 2,
-    UNCHOKE = 1,
-    REQUEST = 6,
-    PIECE = 7,
-    EXTENSION = 20,
+async fn read_loop(
+    mut reader: OwnedReadHalf,
+    pending: &PendingBlocks,
+    incoming: mpsc::Sender<Message>,
+    unchoked: &watch::Sender<bool>,
+    have: watch::Sender<Option<BitVec<u8, Msb0>>>,
+) -> Result<()> {
+    let mut opened = false;
+    loop {
+        let msg = timeout(IDLE_TIMEOUT, Message::read_from(&mut reader))
+            .await
+            .context("peer went idle")??;
+        // A `BITFIELD` must open the connection, though extension handshakes
+        // (BEP 10) and keep-alives may come first; a peer that opens with
+        // anything else has no pieces yet.
+        if !opened && !matches!(msg, Message::Extended { .. } | Message::KeepAlive) {
+            opened = true;
+            if let Message::Bitfield(bits) = msg {
+                have.send_replace(Some(BitVec::from_vec(bits)));
+                continue;
+            }
+            have.send_replace(Some(BitVec::new()));
+        }
+        if let Message::Have(index) = msg {
+            // No larger than the biggest `BITFIELD` we would accept.
+            let index = index as usize;
+            if index < MAX_MESSAGE_LEN as usize * 8 {
+                have.send_modify(|have| {
+                    let have = have.get_or_insert_with(BitVec::new);
+                    if index >= have.len() {
+                        have.resize(index + 1, false);
+                    }
+                    have.set(index, true);
+                });
+            }
+        }
+        match msg {
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let key = (index, begin, block.len() as u32);
+                // Blocks nobody asked for (or that arrive after a choke) are
+                // dropped.
+                if let Some(sender) = pending.lock().unwrap().remove(&key) {
+                    let _ = sender.send(block);
+                }
+            }
+            Message::Choke => {
+                unchoked.send_replace(false);
+                // A choking peer discards our outstanding requests.
+                pending.lock().unwrap().clear();
+            }
+            Message::Unchoke => {
+                unchoked.send_replace(true);
+            }
+            Message::KeepAlive => {}
+            msg => {
+                let _ = incoming.try_send(msg);
+            }
+        }
+    }
 }
 
-impl Message {
-    fn new(id: MessageId, payload: Vec<u8>) -> Self {
-        let length = (mem::size_of::<MessageId>() + payload.len()) as u32;
-        Self {
-            length,
-            id,
-            payload,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::testing::spawn_peer;
+
+    /// Accepts one connection, advertises piece 0 and answers the requests
+    /// for it in reverse order, after an unrequested block and a `HAVE`.
+    async fn stand_in(bind: &str, piece: Vec<u8>, blocks: usize) -> SocketAddr {
+        spawn_peer(bind, move |mut stream| async move {
+            Message::Bitfield(vec![0x80])
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            assert_eq!(
+                Message::read_from(&mut stream).await.unwrap(),
+                Message::Interested
+            );
+            Message::Unchoke.write_to(&mut stream).await.unwrap();
+
+            let mut requests = vec![];
+            while requests.len() < blocks {
+                match Message::read_from(&mut stream).await.unwrap() {
+                    Message::Request {
+                        index,
+                        begin,
+                        length,
+                    } => requests.push((index, begin, length)),
+                    msg => panic!("unexpected {:?}", msg),
+                }
+            }
+            let stray = Message::Piece {
+                index: 0,
+                begin: 0,
+                block: vec![0xff; 3],
+            };
+            stray.write_to(&mut stream).await.unwrap();
+            Message::Have(0).write_to(&mut stream).await.unwrap();
+            for (index, begin, length) in requests.into_iter().rev() {
+                let block = piece[begin as usize..(begin + length) as usize].to_vec();
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            }
+            // Hold the connection open until the client is done.
+            let _ = Message::read_from(&mut stream).await;
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_load_piece_matches_replies() {
+        let piece: Vec<u8> = (0..2 * BLOCK_SIZE + 100).map(|i| i as u8).collect();
+        let address = stand_in("127.0.0.1:0", piece.clone(), 3).await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        assert_eq!(peer.get_pieces().await.unwrap(), [0]);
+        peer.prepare_download().await.unwrap();
+        let loaded = peer.load_piece(0, piece.len() as u32).await.unwrap();
+        assert!(loaded == piece);
+        assert_eq!(peer.recv().await.unwrap(), Message::Have(0));
+    }
+
+    #[tokio::test]
+    async fn test_load_piece_over_ipv6() {
+        let piece = vec![7; 100];
+        let address = stand_in("[::1]:0", piece.clone(), 1).await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        assert_eq!(peer.address, address);
+        assert_eq!(peer.get_pieces().await.unwrap(), [0]);
+        peer.prepare_download().await.unwrap();
+        assert!(peer.load_piece(0, 100).await.unwrap() == piece);
+    }
+
+    #[tokio::test]
+    async fn test_load_piece_fails_on_disconnect() {
+        let address = spawn_peer("127.0.0.1:0", |mut stream| async move {
+            Message::Unchoke.write_to(&mut stream).await.unwrap();
+            // Take a request, then hang up without answering it.
+            let _ = Message::read_from(&mut stream).await;
+        })
+        .await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        peer.prepare_download().await.unwrap();
+        let loaded = tokio::time::timeout(Duration::from_secs(5), peer.load_piece(0, 100)).await;
+        assert!(loaded.unwrap().is_err());
+        assert!(peer.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_unread_messages_are_dropped() {
+        let address = spawn_peer("127.0.0.1:0", |mut stream| async move {
+            for piece in 0..2 * INCOMING_CAPACITY as u32 {
+                Message::Have(piece).write_to(&mut stream).await.unwrap();
+            }
+        })
+        .await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        peer.closed().await;
+        for piece in 0..INCOMING_CAPACITY as u32 {
+            assert_eq!(peer.recv().await.unwrap(), Message::Have(piece));
+        }
+        assert!(peer.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_waits_for_a_stalled_reader() {
+        let (_hang_up, hung_up) = oneshot::channel::<()>();
+        // Never reads past the handshake.
+        let address = spawn_peer("127.0.0.1:0", |stream| async move {
+            let _ = hung_up.await;
+            drop(stream);
+        })
+        .await;
+
+        let peer = Peer::new(address, [3; 20]).await.unwrap();
+        let block = Message::Piece {
+            index: 0,
+            begin: 0,
+            block: vec![0; BLOCK_SIZE as usize],
+        };
+        // Only the socket buffers and our queue fill up, then sending waits.
+        let mut sent = 0;
+        while timeout(Duration::from_millis(200), peer.send(block.clone()))
+            .await
+            .is_ok()
+        {
+            sent += 1;
+            assert!(sent < 10_000, "sending never waited");
         }
     }
 
-    fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.extend(self.length.to_be_bytes());
-        bytes.push(self.id as u8);
-        bytes.extend(self.payload.as_slice());
-        bytes
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alives_hold_an_idle_connection_open() {
+        let (kept_alive, keep_alive) = oneshot::channel();
+        // Answers our keep-alive with one of its own, then goes silent.
+        let address = spawn_peer("127.0.0.1:0", |mut stream| async move {
+            let _ = kept_alive.send(Message::read_from(&mut stream).await.unwrap());
+            Message::KeepAlive.write_to(&mut stream).await.unwrap();
+            while Message::read_from(&mut stream).await.is_ok() {}
+        })
+        .await;
+
+        let peer = Peer::new(address, [3; 20]).await.unwrap();
+        let start = tokio::time::Instant::now();
+        assert_eq!(keep_alive.await.unwrap(), Message::KeepAlive);
+        assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL);
+        peer.closed().await;
+        assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL + IDLE_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_pieces_follow_bitfield_and_have() {
+        let address = spawn_peer("127.0.0.1:0", |mut stream| async move {
+            // Extension handshakes may precede the bitfield.
+            let handshake = Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            };
+            handshake.write_to(&mut stream).await.unwrap();
+            Message::Bitfield(vec![0x80])
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            Message::Have(9).write_to(&mut stream).await.unwrap();
+            let _ = Message::read_from(&mut stream).await;
+        })
+        .await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        // A HAVE is recorded before it is queued for `recv`.
+        assert!(matches!(
+            peer.recv().await.unwrap(),
+            Message::Extended { .. }
+        ));
+        assert_eq!(peer.recv().await.unwrap(), Message::Have(9));
+        assert_eq!(peer.get_pieces().await.unwrap(), [0, 9]);
+        assert!(peer.has_piece(0) && peer.has_piece(9) && !peer.has_piece(1));
+    }
+
+    #[tokio::test]
+    async fn test_missing_bitfield_means_no_pieces() {
+        let address = spawn_peer("127.0.0.1:0", |mut stream| async move {
+            Message::Interested.write_to(&mut stream).await.unwrap();
+            // A late bitfield is not an opening one.
+            Message::Bitfield(vec![0x80])
+                .write_to(&mut stream)
+                .await
+                .unwrap();
+            let _ = Message::read_from(&mut stream).await;
+        })
+        .await;
+
+        let mut peer = Peer::new(address, [3; 20]).await.unwrap();
+        assert!(peer.get_pieces().await.unwrap().is_empty());
+        assert_eq!(peer.recv().await.unwrap(), Message::Interested);
+        assert_eq!(peer.recv().await.unwrap(), Message::Bitfield(vec![0x80]));
     }
 }
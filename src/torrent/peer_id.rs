@@ -0,0 +1,101 @@
+use anyhow::{anyhow, ensure, Result};
+use clap::ValueEnum;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{fmt, sync::OnceLock};
+
+/// Azureus-style client prefix: client code `CR`, version 0.1.0.0.
+pub const DEFAULT_PREFIX: &str = "-CR0100-";
+
+/// The id this process presents to trackers and peers alike.
+static SESSION: OnceLock<PeerId> = OnceLock::new();
+
+/// Which bytes fill the part of a peer id after the client prefix.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Randomness {
+    Digits,
+    #[default]
+    Alphanumeric,
+    /// Any byte value; the most entropy, but not printable.
+    Bytes,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// `prefix` followed by random bytes drawn as `randomness` says.
+    pub fn generate(prefix: &str, randomness: Randomness) -> Result<Self> {
+        ensure!(
+            prefix.len() <= 20,
+            "peer id prefix {:?} is longer than 20 bytes",
+            prefix
+        );
+        let mut rng = rand::thread_rng();
+        let mut id = [0u8; 20];
+        id[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        for byte in &mut id[prefix.len()..] {
+            *byte = match randomness {
+                Randomness::Digits => rng.gen_range(b'0'..=b'9'),
+                Randomness::Alphanumeric => rng.sample(Alphanumeric),
+                Randomness::Bytes => rng.gen(),
+            };
+        }
+        Ok(Self(id))
+    }
+
+    /// Fixes the session id; must happen before anything asks for it.
+    pub fn init_session(id: Self) -> Result<()> {
+        SESSION
+            .set(id)
+            .map_err(|_| anyhow!("session peer id is already set"))
+    }
+
+    /// The session id, generated with the defaults on first use unless
+    /// [`PeerId::init_session`] set one.
+    pub fn session() -> Self {
+        *SESSION.get_or_init(|| {
+            Self::generate(DEFAULT_PREFIX, Randomness::default()).expect("default prefix fits")
+        })
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PeerId({})",
+            String::from_utf8_lossy(&self.0).escape_debug()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::{peer::Handshake, tracker::TrackerRequest};
+
+    #[test]
+    fn test_generate() {
+        let id = PeerId::generate(DEFAULT_PREFIX, Randomness::Digits).unwrap();
+        assert!(id.0.starts_with(b"-CR0100-"));
+        assert!(id.0[8..].iter().all(u8::is_ascii_digit));
+
+        let id = PeerId::generate("-XX1234-", Randomness::Alphanumeric).unwrap();
+        assert!(id.0.starts_with(b"-XX1234-"));
+        assert!(id.0[8..].iter().all(u8::is_ascii_alphanumeric));
+
+        let a = PeerId::generate("", Randomness::Bytes).unwrap();
+        let b = PeerId::generate("", Randomness::Bytes).unwrap();
+        assert_ne!(a, b);
+        assert!(PeerId::generate(&"x".repeat(21), Randomness::Digits).is_err());
+    }
+
+    #[test]
+    fn test_session_is_stable() {
+        assert_eq!(PeerId::session(), PeerId::session());
+        assert!(PeerId::session().0.starts_with(DEFAULT_PREFIX.as_bytes()));
+        assert_eq!(TrackerRequest::new(0).peer_id, PeerId::session());
+        assert_eq!(Handshake::new([0; 20]).peer_id, PeerId::session().0);
+        assert!(PeerId::init_session(PeerId([0; 20])).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::torrent::{peer::BLOCK_SIZE, pipeline::DEFAULT_REQQ};
+use std::{collections::HashMap, net::SocketAddr};
+
+/// Fewest pieces a peer is given at once, so there is always a next one
+/// queued when a piece finishes.
+const MIN_PIECES_PER_PEER: usize = 2;
+
+/// Hands out the pieces still to be fetched, a few per peer at a time. Only
+/// pieces being fetched hold a buffer and have requests waiting for the
+/// pipeline; the rest wait here until a peer that has them frees up.
+pub struct PiecePicker {
+    /// Pieces nobody is fetching, in the order they are handed out.
+    pending: Vec<usize>,
+    in_flight: HashMap<SocketAddr, usize>,
+    per_peer: usize,
+}
+
+impl PiecePicker {
+    /// Each peer gets enough pieces of `piece_len` bytes to keep the deepest
+    /// pipeline busy.
+    pub fn new(num_pieces: usize, piece_len: u64) -> Self {
+        let per_peer = (DEFAULT_REQQ as u64 * BLOCK_SIZE as u64).div_ceil(piece_len.max(1));
+        Self {
+            pending: (0..num_pieces).collect(),
+            in_flight: HashMap::new(),
+            per_peer: (per_peer as usize).max(MIN_PIECES_PER_PEER),
+        }
+    }
+
+    /// Takes pending pieces for the peer at `peer` that `has` says it has,
+    /// up to its share.
+    pub fn pick(&mut self, peer: SocketAddr, has: impl Fn(usize) -> bool) -> Vec<usize> {
+        let in_flight = self.in_flight.entry(peer).or_default();
+        let mut picked = Vec::new();
+        let mut i = 0;
+        while *in_flight < self.per_peer && i < self.pending.len() {
+            if has(self.pending[i]) {
+                picked.push(self.pending.remove(i));
+                *in_flight += 1;
+            } else {
+                i += 1;
+            }
+        }
+        picked
+    }
+
+    /// Frees the peer's place taken by `piece`, which goes back to pending
+    /// unless it was `fetched`.
+    pub fn finished(&mut self, peer: SocketAddr, piece: usize, fetched: bool) {
+        if let Some(in_flight) = self.in_flight.get_mut(&peer) {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+        if !fetched {
+            self.pending.push(piece);
+        }
+    }
+
+    /// Pieces nobody is fetching.
+    pub fn pending(&self) -> &[usize] {
+        &self.pending
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let piece_len = DEFAULT_REQQ as u64 * BLOCK_SIZE as u64;
+        let mut picker = PiecePicker::new(6, piece_len);
+        let (a, b) = ("10.0.0.1:1".parse().unwrap(), "10.0.0.2:1".parse().unwrap());
+
+        assert_eq!(picker.pick(a, |piece| piece % 2 == 1), [1, 3]);
+        assert!(picker.pick(a, |_| true).is_empty());
+        assert_eq!(picker.pick(b, |_| true), [0, 2]);
+        assert_eq!(picker.pending(), [4, 5]);
+
+        picker.finished(a, 1, true);
+        picker.finished(b, 2, false);
+        assert_eq!(picker.pick(a, |_| true), [4]);
+        assert_eq!(picker.pick(b, |piece| piece != 5), [2]);
+        assert!(picker.pick(b, |_| true).is_empty());
+        assert_eq!(picker.pending(), [5]);
+    }
+
+    #[test]
+    fn test_share_covers_the_pipeline() {
+        let picker = PiecePicker::new(1, BLOCK_SIZE as u64);
+        assert_eq!(picker.per_peer, DEFAULT_REQQ);
+        let picker = PiecePicker::new(1, u64::MAX);
+        assert_eq!(picker.per_peer, MIN_PIECES_PER_PEER);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::torrent::peer::BLOCK_SIZE;
+use anyhow::{anyhow, ensure, Result};
+use std::{
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// How many requests a peer queues when its extension handshake doesn't say;
+/// BEP 10 gives this as libtorrent's default.
+pub const DEFAULT_REQQ: usize = 250;
+
+/// Requests kept in flight before there is any throughput to go on.
+const INITIAL_DEPTH: usize = 16;
+const MIN_DEPTH: usize = 2;
+
+/// Aim to keep this much transfer time's worth of requests queued at the
+/// peer, so it never runs dry waiting for our next request.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// A depth set on the command line, used instead of adapting.
+static FIXED_DEPTH: OnceLock<usize> = OnceLock::new();
+
+/// Keeps every peer at `depth` outstanding requests instead of adapting;
+/// must happen before any peer connects.
+pub fn fix_queue_depth(depth: usize) -> Result<()> {
+    ensure!(depth > 0, "queue depth must be at least 1");
+    FIXED_DEPTH
+        .set(depth)
+        .map_err(|_| anyhow!("queue depth is already set"))
+}
+
+/// Bounds the block requests outstanding at one peer. The depth never
+/// exceeds the peer's `reqq` and otherwise follows the measured download
+/// rate, so fast or distant peers get deep queues and slow ones shallow.
+pub struct Pipeline {
+    state: Mutex<State>,
+    /// One permit per request allowed in flight, so each freed slot wakes
+    /// exactly one waiter.
+    slots: Semaphore,
+}
+
+struct State {
+    depth: usize,
+    /// Permits to take out of circulation after the depth shrank while they
+    /// were in use.
+    excess: usize,
+    max_depth: usize,
+    fixed: bool,
+    sample_start: Instant,
+    sample_bytes: u64,
+    /// Smoothed download rate in bytes per second.
+    rate: f64,
+}
+
+/// One outstanding request; frees its place in the pipeline when dropped.
+pub struct Slot<'a> {
+    pipeline: &'a Pipeline,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::with_depth(FIXED_DEPTH.get().copied())
+    }
+
+    fn with_depth(fixed: Option<usize>) -> Self {
+        let depth = fixed.unwrap_or(INITIAL_DEPTH).min(DEFAULT_REQQ);
+        Self {
+            state: Mutex::new(State {
+                depth,
+                excess: 0,
+                max_depth: DEFAULT_REQQ,
+                fixed: fixed.is_some(),
+                sample_start: Instant::now(),
+                sample_bytes: 0,
+                rate: 0.0,
+            }),
+            slots: Semaphore::new(depth),
+        }
+    }
+
+    /// Caps the depth at what the peer said it will queue.
+    pub fn set_reqq(&self, reqq: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_depth = reqq.max(1);
+        let depth = if state.fixed {
+            state.depth.min(state.max_depth)
+        } else {
+            depth_for(state.rate, state.max_depth).max(state.depth.min(state.max_depth))
+        };
+        self.resize(&mut state, depth);
+    }
+
+    /// Waits until fewer than `depth` requests are outstanding.
+    pub async fn acquire(&self) -> Slot<'_> {
+        // The semaphore is never closed.
+        let permit = self.slots.acquire().await.unwrap();
+        Slot {
+            pipeline: self,
+            permit: Some(permit),
+        }
+    }
+
+    /// Adds or withdraws permits to match `depth`. Permits in use when the
+    /// depth shrinks are withdrawn as they come back.
+    fn resize(&self, state: &mut State, depth: usize) {
+        if depth > state.depth {
+            let added = depth - state.depth;
+            let repaid = added.min(state.excess);
+            state.excess -= repaid;
+            self.slots.add_permits(added - repaid);
+        } else {
+            state.excess += state.depth - depth;
+            while state.excess > 0 {
+                let Ok(permit) = self.slots.try_acquire() else {
+                    break;
+                };
+                permit.forget();
+                state.excess -= 1;
+            }
+        }
+        state.depth = depth;
+    }
+
+    fn record(&self, bytes: usize, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.sample_bytes += bytes as u64;
+        let elapsed = now.saturating_duration_since(state.sample_start);
+        if elapsed < SAMPLE_PERIOD {
+            return;
+        }
+
+        let rate = state.sample_bytes as f64 / elapsed.as_secs_f64();
+        state.rate = if state.rate == 0.0 {
+            rate
+        } else {
+            (state.rate + rate) / 2.0
+        };
+        state.sample_start = now;
+        state.sample_bytes = 0;
+        if !state.fixed {
+            let depth = depth_for(state.rate, state.max_depth);
+            self.resize(&mut state, depth);
+        }
+    }
+}
+
+impl Slot<'_> {
+    /// Counts a received block towards the throughput estimate.
+    pub fn completed(self, bytes: usize) {
+        self.pipeline.record(bytes, Instant::now());
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        let mut state = self.pipeline.state.lock().unwrap();
+        if state.excess > 0 {
+            state.excess -= 1;
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Enough blocks to cover [`QUEUE_TIME`] at `rate` bytes per second.
+fn depth_for(rate: f64, max_depth: usize) -> usize {
+    let blocks = (rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+    blocks.clamp(MIN_DEPTH.min(max_depth), max_depth)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::timeout;
+
+    impl Pipeline {
+        fn depth(&self) -> usize {
+            self.state.lock().unwrap().depth
+        }
+    }
+
+    #[test]
+    fn test_depth_for() {
+        let block = BLOCK_SIZE as f64;
+        assert_eq!(depth_for(0.0, DEFAULT_REQQ), MIN_DEPTH);
+        assert_eq!(depth_for(10.0 * block, DEFAULT_REQQ), 30);
+        assert_eq!(depth_for(1000.0 * block, DEFAULT_REQQ), DEFAULT_REQQ);
+        assert_eq!(depth_for(10.0 * block, 8), 8);
+        assert_eq!(depth_for(0.0, 1), 1);
+    }
+
+    #[test]
+    fn test_adapts_to_throughput() {
+        let pipeline = Pipeline::with_depth(None);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        let start = pipeline.state.lock().unwrap().sample_start;
+
+        // No new sample until a full period has passed.
+        pipeline.record(100 * BLOCK_SIZE as usize, start + SAMPLE_PERIOD / 2);
+        assert_eq!(pipeline.depth(), INITIAL_DEPTH);
+        pipeline.record(0, start + SAMPLE_PERIOD);
+        assert_eq!(pipeline.depth(), DEFAULT_REQQ);
+
+        pipeline.set_reqq(40);
+        assert_eq!(pipeline.depth(), 40);
+
+        // A stall halves the smoothed rate each period.
+        pipeline.record(0, start + 2 * SAMPLE_PERIOD);
+        pipeline.record(0, start + 3 * SAMPLE_PERIOD);
+        pipeline.record(0, start + 4 * SAMPLE_PERIOD);
+        pipeline.record(0, start + 5 * SAMPLE_PERIOD);
+        assert_eq!(pipeline.depth(), 19);
+    }
+
+    #[test]
+    fn test_fixed_depth() {
+        let pipeline = Pipeline::with_depth(Some(4));
+        let start = pipeline.state.lock().unwrap().sample_start;
+        pipeline.record(100 * BLOCK_SIZE as usize, start + SAMPLE_PERIOD);
+        assert_eq!(pipeline.depth(), 4);
+        pipeline.set_reqq(3);
+        assert_eq!(pipeline.depth(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_slot() {
+        let pipeline = Pipeline::with_depth(Some(2));
+        let first = pipeline.acquire().await;
+        let _second = pipeline.acquire().await;
+        let short = Duration::from_millis(20);
+        assert!(timeout(short, pipeline.acquire()).await.is_err());
+
+        first.completed(BLOCK_SIZE as usize);
+        assert!(timeout(short, pipeline.acquire()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_shrinking_withdraws_slots_in_use() {
+        let pipeline = Pipeline::with_depth(Some(3));
+        let first = pipeline.acquire().await;
+        let second = pipeline.acquire().await;
+        pipeline.set_reqq(1);
+        let short = Duration::from_millis(20);
+        // The idle slot goes at once; the two in use only as they finish.
+        drop(first);
+        assert!(timeout(short, pipeline.acquire()).await.is_err());
+        drop(second);
+        let third = timeout(short, pipeline.acquire()).await.unwrap();
+        assert!(timeout(short, pipeline.acquire()).await.is_err());
+
+        pipeline.set_reqq(DEFAULT_REQQ);
+        assert_eq!(pipeline.depth(), 1);
+        drop(third);
+        assert_eq!(pipeline.slots.available_permits(), 1);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::torrent::{
+    announcer::Transfer,
+    message::Message,
+    peer::{Peer, BLOCK_SIZE},
+    storage::Storage,
+    torrent::Info,
+};
+use anyhow::{ensure, Result};
+use bitvec::prelude::*;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    path::Path,
+    sync::{atomic::Ordering, Arc, Mutex},
+};
+
+/// Requests queued past this many are dropped; we advertise it as `reqq`.
+pub const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// The verified pieces of a torrent's content that we can upload from.
+pub struct LocalPieces {
+    info: Info,
+    storage: Arc<Storage>,
+    have: Mutex<BitVec<u8, Msb0>>,
+}
+
+impl LocalPieces {
+    /// Content at `data` of which nothing is verified yet; pieces are
+    /// [`add`](Self::add)ed as they are downloaded.
+    pub fn new(info: &Info, data: &Path) -> Result<Self> {
+        Ok(Self {
+            info: info.clone(),
+            storage: Arc::new(Storage::new(info, data)?),
+            have: Mutex::new(BitVec::repeat(false, info.pieces().len())),
+        })
+    }
+
+    /// Hashes whatever is at `data`, laid out as [`Storage::new`] describes,
+    /// and keeps the pieces that match. Missing or short files just leave
+    /// their pieces out.
+    pub fn verify(info: &Info, data: &Path) -> Result<Self> {
+        let storage = Storage::new(info, data)?;
+        let hashes = info.pieces();
+        let mut have = BitVec::repeat(false, hashes.len());
+        for (piece, hash) in hashes.iter().enumerate() {
+            let offset = info.piece_offset(piece)?;
+            let len = info.piece_len(piece)? as usize;
+            if let Ok(data) = storage.read(offset, len) {
+                have.set(piece, *Sha1::digest(&data) == hash[..]);
+            }
+        }
+        Ok(Self {
+            info: info.clone(),
+            storage: Arc::new(storage),
+            have: Mutex::new(have),
+        })
+    }
+
+    /// Marks `piece` as verified and available for upload.
+    pub fn add(&self, piece: usize) {
+        self.have.lock().unwrap().set(piece, true);
+    }
+
+    pub fn count(&self) -> usize {
+        self.have.lock().unwrap().count_ones()
+    }
+
+    /// Bytes of content we don't have.
+    pub fn left(&self) -> u64 {
+        self.have
+            .lock()
+            .unwrap()
+            .iter_zeros()
+            .map(|piece| self.info.piece_len(piece).map_or(0, u64::from))
+            .sum()
+    }
+
+    pub fn has(&self, piece: usize) -> bool {
+        self.have.lock().unwrap().get(piece).is_some_and(|bit| *bit)
+    }
+
+    /// The `BITFIELD` payload: one bit per piece, high bit first, with the
+    /// spare bits of the last byte clear.
+    pub fn bitfield(&self) -> Vec<u8> {
+        self.have.lock().unwrap().clone().into_vec()
+    }
+
+    /// Rejects requests that reach outside their piece or ask for more than
+    /// a block; BEP 3 has peers close connections that do either.
+    fn check_request(&self, index: u32, begin: u32, length: u32) -> Result<()> {
+        let piece_len = self.info.piece_len(index as usize)?;
+        ensure!(
+            length > 0 && length <= BLOCK_SIZE,
+            "requested {} bytes, more than a block",
+            length
+        );
+        ensure!(
+            begin as u64 + length as u64 <= piece_len as u64,
+            "request for {}+{} runs past the end of piece {}",
+            begin,
+            length,
+            index
+        );
+        Ok(())
+    }
+
+    async fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>> {
+        let offset = self.info.piece_offset(index as usize)? + begin as u64;
+        self.storage.read_async(offset, length as usize).await
+    }
+}
+
+/// Uploads to one peer until it disconnects, starting with our `BITFIELD`
+/// unless we have nothing yet. Every interested peer is unchoked; requests
+/// while it is choked are discarded, as are requests for pieces we don't
+/// have, and a `CANCEL` withdraws a request not yet served. Requests out of
+/// bounds end the connection.
+///
+/// The `BITFIELD` is queued before this returns, so it goes out ahead of
+/// anything else sent on the connection afterwards.
+pub fn serve(
+    mut peer: Peer,
+    pieces: Arc<LocalPieces>,
+    transfer: Arc<Transfer>,
+) -> impl Future<Output = Result<()>> + Send + 'static {
+    let opened = if pieces.count() > 0 {
+        peer.try_send(Message::Bitfield(pieces.bitfield()))
+    } else {
+        Ok(())
+    };
+    async move {
+        opened?;
+        serve_requests(&mut peer, &pieces, &transfer).await
+    }
+}
+
+async fn serve_requests(peer: &mut Peer, pieces: &LocalPieces, transfer: &Transfer) -> Result<()> {
+    let mut choking = true;
+    let mut queue = VecDeque::new();
+
+    loop {
+        // Messages that have already arrived come first, so a CANCEL still
+        // catches the request it is meant for.
+        let msg = tokio::select! {
+            biased;
+            msg = peer.recv() => Some(msg?),
+            _ = std::future::ready(()), if !queue.is_empty() => None,
+        };
+        let Some(msg) = msg else {
+            let (index, begin, length) = queue.pop_front().unwrap();
+            let block = pieces.read_block(index, begin, length).await?;
+            peer.send(Message::Piece {
+                index,
+                begin,
+                block,
+            })
+            .await?;
+            transfer
+                .uploaded
+                .fetch_add(length as u64, Ordering::Relaxed);
+            continue;
+        };
+
+        match msg {
+            Message::Interested if choking => {
+                choking = false;
+                peer.send(Message::Unchoke).await?;
+            }
+            Message::NotInterested if !choking => {
+                choking = true;
+                queue.clear();
+                peer.send(Message::Choke).await?;
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                pieces.check_request(index, begin, length)?;
+                if !choking && pieces.has(index as usize) && queue.len() < MAX_QUEUED_REQUESTS {
+                    queue.push_back((index, begin, length));
+                }
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => queue.retain(|request| *request != (index, begin, length)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::{
+        create::{create_torrent, CreateOptions},
+        testing::{accept_peer, create_options},
+    };
+    use std::fs;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    fn options() -> CreateOptions {
+        create_options(BLOCK_SIZE as u64 * 2)
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let content: Vec<u8> = (0..BLOCK_SIZE * 5).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        let torrent = create_torrent(&path, &options()).unwrap();
+
+        let pieces = LocalPieces::verify(&torrent.info, &path).unwrap();
+        assert_eq!(pieces.count(), 3);
+        assert_eq!(pieces.left(), 0);
+        assert_eq!(pieces.bitfield(), [0b1110_0000]);
+
+        let mut corrupt = content.clone();
+        corrupt[BLOCK_SIZE as usize * 2] ^= 1;
+        fs::write(&path, &corrupt).unwrap();
+        let pieces = LocalPieces::verify(&torrent.info, &path).unwrap();
+        assert_eq!(pieces.bitfield(), [0b1010_0000]);
+        assert_eq!(pieces.left(), BLOCK_SIZE as u64 * 2);
+        assert!(!pieces.has(1) && pieces.has(2) && !pieces.has(3));
+
+        let pieces = LocalPieces::verify(&torrent.info, &dir.path().join("gone")).unwrap();
+        assert_eq!(pieces.count(), 0);
+        assert_eq!(pieces.left(), content.len() as u64);
+    }
+
+    #[test]
+    fn test_check_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        fs::write(&path, vec![7u8; BLOCK_SIZE as usize * 3]).unwrap();
+        let torrent = create_torrent(&path, &options()).unwrap();
+        let pieces = LocalPieces::verify(&torrent.info, &path).unwrap();
+
+        assert!(pieces.check_request(0, 0, BLOCK_SIZE).is_ok());
+        assert!(pieces.check_request(0, BLOCK_SIZE, BLOCK_SIZE).is_ok());
+        assert!(pieces.check_request(1, 0, BLOCK_SIZE).is_ok());
+        // The last piece is only one block long.
+        assert!(pieces.check_request(1, BLOCK_SIZE, 1).is_err());
+        assert!(pieces.check_request(2, 0, 1).is_err());
+        assert!(pieces.check_request(0, 0, BLOCK_SIZE + 1).is_err());
+        assert!(pieces.check_request(0, 0, 0).is_err());
+        assert!(pieces.check_request(0, u32::MAX, BLOCK_SIZE).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_while_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let content: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        let torrent = create_torrent(&path, &options()).unwrap();
+        let pieces = Arc::new(LocalPieces::new(&torrent.info, &path).unwrap());
+        assert_eq!(pieces.count(), 0);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let info_hash = torrent.info_hash();
+        tokio::spawn({
+            let pieces = pieces.clone();
+            async move {
+                let peer = Peer::new(address, info_hash).await.unwrap();
+                serve(peer, pieces, Transfer::new(0)).await
+            }
+        });
+
+        let mut stream = accept_peer(&listener).await;
+        // With nothing to offer there is no bitfield, but interest is still
+        // answered.
+        Message::Interested.write_to(&mut stream).await.unwrap();
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            Message::Unchoke
+        );
+
+        pieces.add(1);
+        assert_eq!(pieces.bitfield(), [0b0100_0000]);
+        assert_eq!(pieces.left(), BLOCK_SIZE as u64 * 2);
+        Message::Request {
+            index: 1,
+            begin: 0,
+            length: BLOCK_SIZE,
+        }
+        .write_to(&mut stream)
+        .await
+        .unwrap();
+        let start = BLOCK_SIZE as usize * 2;
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            Message::Piece {
+                index: 1,
+                begin: 0,
+                block: content[start..start + BLOCK_SIZE as usize].to_vec(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let content: Vec<u8> = (0..BLOCK_SIZE * 4).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+        let torrent = create_torrent(&path, &options()).unwrap();
+        let pieces = Arc::new(LocalPieces::verify(&torrent.info, &path).unwrap());
+        let transfer = Transfer::new(0);
+
+        // The leecher listens and we connect out to it, as we would to peers
+        // a tracker hands us.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let info_hash = torrent.info_hash();
+        let seeder = tokio::spawn({
+            let transfer = transfer.clone();
+            async move {
+                let peer = Peer::new(address, info_hash).await.unwrap();
+                serve(peer, pieces, transfer).await
+            }
+        });
+
+        let mut stream = accept_peer(&listener).await;
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            Message::Bitfield(vec![0b1100_0000])
+        );
+
+        let request = |index, begin| Message::Request {
+            index,
+            begin,
+            length: BLOCK_SIZE,
+        };
+        let block = |index: u32, begin: u32| {
+            let start = (index * BLOCK_SIZE * 2 + begin) as usize;
+            Message::Piece {
+                index,
+                begin,
+                block: content[start..start + BLOCK_SIZE as usize].to_vec(),
+            }
+        };
+
+        // Requests while choked are discarded.
+        request(0, 0).write_to(&mut stream).await.unwrap();
+        Message::Interested.write_to(&mut stream).await.unwrap();
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            Message::Unchoke
+        );
+
+        let cancel = Message::Cancel {
+            index: 1,
+            begin: 0,
+            length: BLOCK_SIZE,
+        };
+        let batch = [
+            request(0, BLOCK_SIZE),
+            request(1, 0),
+            cancel,
+            request(1, BLOCK_SIZE),
+        ]
+        .map(|msg| msg.encode())
+        .concat();
+        stream.write_all(&batch).await.unwrap();
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            block(0, BLOCK_SIZE)
+        );
+        assert_eq!(
+            Message::read_from(&mut stream).await.unwrap(),
+            block(1, BLOCK_SIZE)
+        );
+        assert_eq!(
+            transfer.uploaded.load(Ordering::Relaxed),
+            BLOCK_SIZE as u64 * 2
+        );
+
+        // A request past the end of the content closes the connection.
+        request(2, 0).write_to(&mut stream).await.unwrap();
+        assert!(seeder.await.unwrap().is_err());
+        assert!(Message::read_from(&mut stream).await.is_err());
+    }
+}
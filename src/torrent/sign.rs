@@ -1,9 +1,9 @@
 pub struct Sign;
 
 impl Sign {
-    pub const I: u8 = 'i' as u8;
-    pub const L: u8 = 'l' as u8;
-    pub const E: u8 = 'e' as u8;
-    pub const COLON: u8 = ':' as u8;
-    pub const D: u8 = 'd' as u8;
+    pub const I: u8 = b'i';
+    pub const L: u8 = b'l';
+    pub const E: u8 = b'e';
+    pub const COLON: u8 = b':';
+    pub const D: u8 = b'd';
 }
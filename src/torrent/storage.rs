@@ -0,0 +1,233 @@
+use crate::torrent::torrent::Info;
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::task;
+
+/// Names Windows refuses to create, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A file on disk holding `length` bytes of the torrent's content starting
+/// at `offset`.
+#[derive(Debug, PartialEq)]
+pub struct FileSlot {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Maps the torrent's content, which pieces index as one contiguous byte
+/// range, onto the files it is made of.
+pub struct Storage {
+    files: Vec<FileSlot>,
+}
+
+impl Storage {
+    /// A single-file torrent is stored at `output` itself; a multi-file
+    /// torrent becomes the directory `output/<name>/` with each file at its
+    /// listed path below it.
+    pub fn new(info: &Info, output: &Path) -> Result<Self> {
+        let Some(files) = info.files() else {
+            let files = vec![FileSlot {
+                path: output.to_path_buf(),
+                offset: 0,
+                length: info.file_len(),
+            }];
+            return Ok(Self { files });
+        };
+
+        let root = output.join(sanitize_component(info.name())?);
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                if path.is_empty() {
+                    bail!("file at offset {} has an empty path", offset);
+                }
+                let mut file_path = root.clone();
+                for component in path {
+                    file_path.push(sanitize_component(component)?);
+                }
+                let slot = FileSlot {
+                    path: file_path,
+                    offset,
+                    length,
+                };
+                offset += length;
+                Ok(slot)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self { files })
+    }
+
+    /// Creates every file (and its parent directories) at its final size.
+    pub fn create(&self) -> Result<()> {
+        for file in &self.files {
+            if let Some(parent) = file.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&file.path)
+                .with_context(|| format!("failed to create {}", file.path.display()))?
+                .set_len(file.length)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` in the torrent's content, splitting it over
+    /// every file the range touches.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<()> {
+        for (file, file_offset, range) in self.spans(offset, data.len()) {
+            let mut handle = OpenOptions::new().write(true).open(&file.path)?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.write_all(&data[range])?;
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `offset` in the torrent's content, gathering them
+    /// from every file the range touches.
+    pub fn read(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        for (file, file_offset, range) in self.spans(offset, len) {
+            let mut handle = fs::File::open(&file.path)
+                .with_context(|| format!("failed to open {}", file.path.display()))?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.read_exact(&mut data[range])?;
+        }
+        Ok(data)
+    }
+
+    /// [`write`](Self::write) on the blocking pool, keeping file I/O off the
+    /// async workers.
+    pub async fn write_async(self: &Arc<Self>, offset: u64, data: Vec<u8>) -> Result<()> {
+        let storage = self.clone();
+        task::spawn_blocking(move || storage.write(offset, &data))
+            .await
+            .context("Task panicked")?
+    }
+
+    /// [`read`](Self::read) on the blocking pool.
+    pub async fn read_async(self: &Arc<Self>, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let storage = self.clone();
+        task::spawn_blocking(move || storage.read(offset, len))
+            .await
+            .context("Task panicked")?
+    }
+
+    /// Splits the content range `offset..offset + len` into per-file pieces:
+    /// the file, the offset within it, and the matching range of the buffer.
+    fn spans(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Iterator<Item = (&FileSlot, u64, Range<usize>)> {
+        let end = offset + len as u64;
+        self.files.iter().filter_map(move |file| {
+            let file_end = file.offset + file.length;
+            if file_end <= offset || file.offset >= end {
+                return None;
+            }
+            let start = offset.max(file.offset);
+            let stop = end.min(file_end);
+            let range = (start - offset) as usize..(stop - offset) as usize;
+            Some((file, start - file.offset, range))
+        })
+    }
+}
+
+/// Checks that a single path component from the metainfo can't escape the
+/// download directory or name something the OS treats specially.
+fn sanitize_component(component: &str) -> Result<&str> {
+    let mut components = Path::new(component).components();
+    let is_plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if !is_plain || component.contains(['/', '\\', '\0']) {
+        return Err(anyhow!("unsafe path component {:?}", component));
+    }
+
+    let stem = component.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()))
+    {
+        bail!("reserved file name {:?}", component);
+    }
+    Ok(component)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::torrent::Torrent;
+
+    fn multi_file_info(files: &str) -> Info {
+        let content = format!(
+            "d8:announce3:url4:infod5:filesl{}e4:name3:dir12:piece lengthi4e6:pieces0:ee",
+            files
+        );
+        Torrent::from_bytes(content.as_bytes()).unwrap().info
+    }
+
+    #[test]
+    fn test_sanitize_component() {
+        assert!(sanitize_component("movie.mkv").is_ok());
+        assert!(sanitize_component("..hidden").is_ok());
+        for unsafe_name in [
+            "..", ".", "", "/etc", "a/b", "a\\b", "CON", "nul.txt", "Com1",
+        ] {
+            assert!(
+                sanitize_component(unsafe_name).is_err(),
+                "{:?}",
+                unsafe_name
+            );
+        }
+    }
+
+    #[test]
+    fn test_layout_rejects_traversal() {
+        let info = multi_file_info("d6:lengthi1e4:pathl2:..6:passwdee");
+        assert!(Storage::new(&info, Path::new("out")).is_err());
+    }
+
+    #[test]
+    fn test_write_spans_file_boundaries() {
+        let info = multi_file_info(
+            "d6:lengthi3e4:pathl1:aeed6:lengthi0e4:pathl5:empty1:beed6:lengthi5e4:pathl3:sub1:cee",
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(&info, dir.path()).unwrap();
+        let root = dir.path().join("dir");
+        assert_eq!(
+            storage.files[2],
+            FileSlot {
+                path: root.join("sub").join("c"),
+                offset: 3,
+                length: 5
+            }
+        );
+
+        storage.create().unwrap();
+        // A 4-byte piece straddling `a` and `sub/c`, then the rest.
+        storage.write(0, b"abcd").unwrap();
+        storage.write(4, b"efgh").unwrap();
+
+        assert_eq!(fs::read(root.join("a")).unwrap(), b"abc");
+        assert_eq!(fs::read(root.join("empty").join("b")).unwrap(), b"");
+        assert_eq!(fs::read(root.join("sub").join("c")).unwrap(), b"defgh");
+        assert_eq!(storage.read(2, 3).unwrap(), b"cde");
+    }
+}
@@ -0,0 +1,69 @@
+//! Fixtures shared by the tests: stand-in peers and trackers run on
+//! loopback sockets, and a baseline for creating torrents.
+
+use crate::torrent::create::CreateOptions;
+use std::{future::Future, net::SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+/// Options for a single-tracker, public torrent of `piece_length` pieces.
+pub fn create_options(piece_length: u64) -> CreateOptions {
+    CreateOptions {
+        announce: vec!["http://a/announce".to_string()],
+        comment: None,
+        piece_length: Some(piece_length),
+        private: false,
+        source: None,
+    }
+}
+
+/// Accepts one peer connection on `listener` and answers its handshake
+/// with the same bytes.
+pub async fn accept_peer(listener: &TcpListener) -> TcpStream {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut handshake = [0u8; 68];
+    stream.read_exact(&mut handshake).await.unwrap();
+    stream.write_all(&handshake).await.unwrap();
+    stream
+}
+
+/// Listens on `bind` and runs `serve` on the listener in the background.
+pub async fn spawn_tcp<F, Fut>(bind: &str, serve: F) -> SocketAddr
+where
+    F: FnOnce(TcpListener) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send,
+{
+    let listener = TcpListener::bind(bind).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener));
+    address
+}
+
+/// A stand-in peer on `bind`: the first connection has its handshake
+/// answered, then `serve` takes over.
+pub async fn spawn_peer<F, Fut>(bind: &str, serve: F) -> SocketAddr
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    spawn_tcp(bind, |listener| async move {
+        serve(accept_peer(&listener).await).await;
+    })
+    .await
+}
+
+/// Binds a UDP socket on `bind` and runs `serve` on it in the background.
+pub async fn spawn_udp<F, Fut>(bind: &str, serve: F) -> SocketAddr
+where
+    F: FnOnce(UdpSocket) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send,
+{
+    let socket = UdpSocket::bind(bind).await.unwrap();
+    let address = socket.local_addr().unwrap();
+    tokio::spawn(serve(socket));
+    address
+}
@@ -1,81 +1,330 @@
 use crate::torrent::{
+    announcer::{Announcer, Transfer},
+    listener::Listener,
     magnet::Magnet,
+    message::Message,
+    parser::{self, Limits, ParseOptions},
     peer::Peer,
-    tracker::{TrackerRequest, TrackerResponse},
+    picker::PiecePicker,
+    seed::{self, LocalPieces},
+    storage::Storage,
+    tracker::{TrackerRequest, TrackerTiers},
 };
-use anyhow::{anyhow, Context, Result};
-use rand::seq::SliceRandom;
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value as BencodedValue;
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
-    path::PathBuf,
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc, OnceLock},
 };
-use tokio::{net::UdpSocket, task::JoinSet};
-use url::form_urlencoded;
+use tokio::{
+    sync::{mpsc, Mutex},
+    task::{self, JoinSet},
+    time::{interval, timeout, Duration},
+};
+
+/// How long a peer that connected to us gets to say what it has, and any
+/// peer to unchoke us once we're interested.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often pending pieces are offered to every peer again.
+const WAITING_RECHECK: Duration = Duration::from_secs(1);
+
+/// Peers connecting to `listener` for `info_hash`; never any without one.
+fn incoming_peers(
+    listener: Option<&Listener>,
+    info_hash: [u8; 20],
+) -> mpsc::UnboundedReceiver<Peer> {
+    match listener {
+        Some(listener) => listener.register(info_hash),
+        None => mpsc::unbounded_channel().1,
+    }
+}
+
+/// Keys we don't model, kept byte for byte so that re-encoding reproduces
+/// the original.
+pub type ExtraKeys = BTreeMap<ByteBuf, BencodedValue>;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Torrent {
+    /// May be absent when `announce-list` is given.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<ByteBuf>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<ByteBuf>,
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
     pub info: Info,
+    #[serde(flatten)]
+    pub extra: ExtraKeys,
+    /// SHA-1 of the `info` dict exactly as it was encoded in the source bytes.
+    #[serde(skip)]
+    info_hash: [u8; 20],
+    /// Shuffled on first use, then shared so that promotions carry over from
+    /// one announce to the next.
+    #[serde(skip)]
+    trackers: OnceLock<Arc<Mutex<TrackerTiers>>>,
+}
+
+/// BEP 19 web seeds; a single URL may be given without a list around it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl UrlList {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            UrlList::Single(url) => std::slice::from_ref(url),
+            UrlList::Multiple(urls) => urls,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Info {
     #[serde(rename = "piece length")]
-    pub piece_length: u32,
+    pub piece_length: u64,
     #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
     name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<ByteBuf>,
     #[serde(flatten)]
     additional: Additional,
+    #[serde(flatten)]
+    pub extra: ExtraKeys,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
-#[serde(untagged)]
+#[serde(try_from = "AdditionalKeys", into = "AdditionalKeys")]
 enum Additional {
-    SingleFile { length: u32 },
+    SingleFile { length: u64 },
     MultiFile { files: Vec<File> },
 }
 
+/// Wire form of [`Additional`]. Being a plain struct, it only claims its own
+/// keys when flattened, leaving everything else to `Info::extra`.
+#[derive(Serialize, Deserialize)]
+struct AdditionalKeys {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<File>>,
+}
+
+impl TryFrom<AdditionalKeys> for Additional {
+    type Error = &'static str;
+
+    fn try_from(keys: AdditionalKeys) -> Result<Self, Self::Error> {
+        match (keys.length, keys.files) {
+            (Some(length), None) => Ok(Additional::SingleFile { length }),
+            (None, Some(files)) => {
+                // Checked here once so that `Info::file_len` can't overflow.
+                files
+                    .iter()
+                    .try_fold(0u64, |total, f| total.checked_add(f.length))
+                    .ok_or("total length of files overflows")?;
+                Ok(Additional::MultiFile { files })
+            }
+            _ => Err("info must have exactly one of `length` or `files`"),
+        }
+    }
+}
+
+impl From<Additional> for AdditionalKeys {
+    fn from(additional: Additional) -> Self {
+        match additional {
+            Additional::SingleFile { length } => Self {
+                length: Some(length),
+                files: None,
+            },
+            Additional::MultiFile { files } => Self {
+                length: None,
+                files: Some(files),
+            },
+        }
+    }
+}
+
 impl Info {
+    /// An info dict without piece hashes yet, for a single file of `length`.
+    pub fn single_file(name: String, length: u64, piece_length: u64) -> Self {
+        Self::with_additional(name, piece_length, Additional::SingleFile { length })
+    }
+
+    /// An info dict without piece hashes yet, for `files` given as (path
+    /// components, length) in content order.
+    pub fn multi_file(name: String, files: Vec<(Vec<String>, u64)>, piece_length: u64) -> Self {
+        let files = files
+            .into_iter()
+            .map(|(path, length)| File {
+                length,
+                path,
+                extra: ExtraKeys::new(),
+            })
+            .collect();
+        Self::with_additional(name, piece_length, Additional::MultiFile { files })
+    }
+
+    fn with_additional(name: String, piece_length: u64, additional: Additional) -> Self {
+        Self {
+            piece_length,
+            pieces: Vec::new(),
+            name,
+            private: None,
+            source: None,
+            additional,
+            extra: ExtraKeys::new(),
+        }
+    }
+
     pub fn pieces(&self) -> Vec<Vec<u8>> {
         self.pieces.chunks(20).map(|c| c.to_vec()).collect()
     }
 
-    pub fn file_len(&self) -> u32 {
+    pub fn file_len(&self) -> u64 {
         match &self.additional {
             Additional::SingleFile { length } => *length,
             Additional::MultiFile { files } => files.iter().map(|f| f.length).sum(),
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Files in content order as (path components, length). `None` for a
+    /// single-file torrent, whose only file is called `name`.
+    pub fn files(&self) -> Option<Vec<(&[String], u64)>> {
+        match &self.additional {
+            Additional::SingleFile { .. } => None,
+            Additional::MultiFile { files } => Some(
+                files
+                    .iter()
+                    .map(|f| (f.path.as_slice(), f.length))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Byte offset of `piece` within the torrent's content.
+    pub fn piece_offset(&self, piece: usize) -> Result<u64> {
+        u64::try_from(piece)
+            .ok()
+            .and_then(|piece| piece.checked_mul(self.piece_length))
+            .filter(|&offset| offset < self.file_len())
+            .ok_or(anyhow!("piece {} is out of range", piece))
+    }
+
+    /// Length of `piece`; only the last piece may be shorter than
+    /// `piece_length`. Pieces are addressed with 32-bit offsets on the wire.
+    pub fn piece_len(&self, piece: usize) -> Result<u32> {
+        let offset = self.piece_offset(piece)?;
+        let len = self.piece_length.min(self.file_len() - offset);
+        u32::try_from(len).context("piece length does not fit in 32 bits")
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
 struct File {
-    length: u32,
+    length: u64,
     path: Vec<String>,
+    #[serde(flatten)]
+    extra: ExtraKeys,
 }
 
 impl Torrent {
     pub fn new(file_name: PathBuf) -> Result<Self> {
         let content = std::fs::read(file_name)?;
-        Ok(serde_bencode::from_bytes::<Self>(&content)?)
+        Self::from_bytes(&content)
+    }
+
+    /// Wraps a freshly built info dict; its hash is taken over our own
+    /// canonical encoding of it.
+    pub fn from_info(announce: String, info: Info) -> Result<Self> {
+        let info_hash = Sha1::digest(serde_bencode::to_bytes(&info)?).into();
+        Ok(Self {
+            announce,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info,
+            extra: ExtraKeys::new(),
+            info_hash,
+            trackers: OnceLock::new(),
+        })
+    }
+
+    pub fn from_bytes(content: &[u8]) -> Result<Self> {
+        // Checked before serde sees it: unknown keys are decoded recursively.
+        let options = ParseOptions {
+            limits: Some(Limits::default()),
+            ..Default::default()
+        };
+        let root = parser::parse_with(content, &options)?;
+        let mut torrent = serde_bencode::from_bytes::<Self>(content)?;
+        let info = root.get(b"info").context("missing info dictionary")?;
+        torrent.info_hash = Sha1::digest(info.raw(content)).into();
+        Ok(torrent)
     }
 
-    pub fn from_magnet_and_metadata(magnet: Magnet, metadata: Info) -> Result<Self> {
+    /// Builds a torrent from the raw `info` dict received over ut_metadata,
+    /// checking that it hashes to the info hash the magnet link asked for.
+    pub fn from_magnet_and_metadata(magnet: Magnet, metadata: Vec<u8>) -> Result<Self> {
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+        ensure!(
+            info_hash == magnet.info_hash,
+            "metadata hash {} does not match magnet info hash {}",
+            hex::encode(info_hash),
+            hex::encode(magnet.info_hash)
+        );
         Ok(Self {
             announce: magnet.tracker_url.unwrap().to_string(),
-            info: metadata,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            url_list: None,
+            info: parser::from_bytes_limited::<Info>(&metadata, Limits::default())?,
+            extra: ExtraKeys::new(),
+            info_hash,
+            trackers: OnceLock::new(),
         })
     }
 
-    pub fn info_hash(&self) -> Result<[u8; 20]> {
-        Ok(Sha1::digest(serde_bencode::to_bytes(&self.info)?).into())
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
     }
 
-    pub fn len(&self) -> u32 {
+    pub fn len(&self) -> u64 {
         self.info.file_len()
     }
 
@@ -83,52 +332,51 @@ impl Torrent {
         self.info.pieces()
     }
 
+    /// The BEP 12 tracker tiers; `announce` alone is used only when there is
+    /// no usable `announce-list`.
+    fn tiers(&self) -> Vec<Vec<String>> {
+        match &self.announce_list {
+            Some(tiers) if tiers.iter().any(|tier| !tier.is_empty()) => tiers.clone(),
+            _ => vec![vec![self.announce.clone()]],
+        }
+    }
+
+    /// Every tracker URL, tier by tier, in the order the torrent lists them.
+    pub fn tracker_urls(&self) -> Vec<String> {
+        self.tiers().into_iter().flatten().collect()
+    }
+
+    /// The tracker tiers every announce for this torrent goes through.
+    pub fn trackers(&self) -> Arc<Mutex<TrackerTiers>> {
+        self.trackers
+            .get_or_init(|| Arc::new(Mutex::new(TrackerTiers::new(self.tiers()))))
+            .clone()
+    }
+
     pub async fn get_peer_addrs(&self) -> Result<Vec<SocketAddr>> {
-        let info_hash_str: String = form_urlencoded::byte_serialize(&self.info_hash()?).collect();
         let request = TrackerRequest::new(self.len());
-        let announce = &self.announce;
-        if announce.starts_with("http") {
-            let params = serde_urlencoded::to_string(&request)?;
-            let url = format!("{}?{}&info_hash={}", announce, params, info_hash_str);
-            let response = reqwest::get(url).await?;
-            let tracker_response =
-                serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)?;
-            let peer_addrs = tracker_response.peers();
-            println!("Found peers: {:?}", peer_addrs);
-            Ok(peer_addrs)
-        } else if announce.starts_with("udp") {
-            let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
-            let address = Self::parse_udp_url(announce)?;
-            sock.connect(address).await?;
-            Ok(vec![])
-        } else {
-            Err(anyhow!("Unsupported tracker protocol"))
-        }
-    }
-
-    fn parse_udp_url(url: &str) -> Result<String> {
-        let parts: Vec<&str> = url.split(':').collect();
-        let host = parts[1].trim_start_matches('/');
-        let port = parts[2];
-        let addr = format!("{}:{}", host, port);
-        Ok(addr)
+        let peer_addrs = self
+            .trackers()
+            .lock()
+            .await
+            .announce(&self.info_hash(), &request)
+            .await?
+            .peers;
+        println!("Found peers: {:?}", peer_addrs);
+        Ok(peer_addrs)
     }
 
     pub async fn download_piece(&self, piece: usize) -> Result<Vec<u8>> {
         let peer_addrs = self.get_peer_addrs().await?;
-        let info_hash = self.info_hash()?;
+        let info_hash = self.info_hash();
         for peer_address in peer_addrs {
             match Peer::new(peer_address, info_hash).await {
                 Ok(mut peer) => {
                     let pieces = peer.get_pieces().await?;
                     if pieces.contains(&piece) {
-                        let piece = piece as u32;
-                        let piece_len = std::cmp::min(
-                            self.info.piece_length,                      // piece_len
-                            self.len() - piece * self.info.piece_length, // last piece
-                        );
+                        let piece_len = self.info.piece_len(piece)?;
                         peer.prepare_download().await?;
-                        let piece_data = peer.load_piece(piece, piece_len).await?;
+                        let piece_data = peer.load_piece(piece as u32, piece_len).await?;
                         return Ok(piece_data);
                     }
                 }
@@ -138,51 +386,216 @@ impl Torrent {
         Err(anyhow!("Could not find peer"))
     }
 
-    pub async fn download(&self) -> Result<Vec<u8>> {
-        let peer_addrs = self.get_peer_addrs().await?;
+    /// Downloads every piece into `output`, laid out as described by
+    /// [`Storage::new`], keeping the trackers informed throughout.
+    /// Peers that connect to `listener` for this torrent are used too.
+    pub async fn download(&self, output: &Path, listener: Option<&Listener>) -> Result<()> {
+        let incoming = incoming_peers(listener, self.info_hash());
+        let transfer = Transfer::new(self.len());
+        let (announcer, peer_addrs) =
+            Announcer::start(self.trackers(), self.info_hash(), transfer.clone()).await?;
+        let listening = listener.is_some();
+        let result = tokio::select! {
+            result = self.download_from(peer_addrs, incoming, listening, output, &transfer) => result,
+            _ = tokio::signal::ctrl_c() => Err(anyhow!("Interrupted")),
+        };
+        if result.is_ok() {
+            announcer.completed();
+        }
+        announcer.stop().await?;
+        result
+    }
+
+    /// Shares the verified pieces found at `data`, laid out as for
+    /// [`Torrent::download`], with the peers the trackers hand us, on every
+    /// announce, and those that connect to `listener`, until interrupted.
+    pub async fn seed(&self, data: &Path, listener: Option<&Listener>) -> Result<()> {
+        let pieces = {
+            let info = self.info.clone();
+            let data = data.to_path_buf();
+            task::spawn_blocking(move || LocalPieces::verify(&info, &data))
+                .await
+                .context("Task panicked")??
+        };
+        let pieces = Arc::new(pieces);
+        println!("Verified {}/{} pieces", pieces.count(), self.pieces().len());
+        ensure!(
+            pieces.count() > 0,
+            "no verified pieces at {}",
+            data.display()
+        );
+
+        let info_hash = self.info_hash();
+        let mut incoming = incoming_peers(listener, info_hash);
+        let transfer = Transfer::new(pieces.left());
+        let (announcer, peer_addrs) =
+            Announcer::start(self.trackers(), info_hash, transfer.clone()).await?;
+        let mut announced = announcer.peers();
+        let mut join_set = JoinSet::new();
+        // Peers we dialled and are still serving, so re-announces don't
+        // connect to them twice.
+        let mut dialled = HashSet::new();
+        let dial = |join_set: &mut JoinSet<_>,
+                    dialled: &mut HashSet<SocketAddr>,
+                    peer_address: SocketAddr| {
+            if !dialled.insert(peer_address) {
+                return;
+            }
+            let pieces = pieces.clone();
+            let transfer = transfer.clone();
+            join_set.spawn(async move {
+                let result = match Peer::new(peer_address, info_hash).await {
+                    Ok(peer) => seed::serve(peer, pieces, transfer).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    eprintln!("{} -> {}", peer_address, e);
+                }
+                Some(peer_address)
+            });
+        };
+        for peer_address in peer_addrs {
+            dial(&mut join_set, &mut dialled, peer_address);
+        }
+
+        loop {
+            tokio::select! {
+                Some(peer) = incoming.recv() => {
+                    let pieces = pieces.clone();
+                    let transfer = transfer.clone();
+                    join_set.spawn(async move {
+                        let peer_address = peer.address;
+                        if let Err(e) = seed::serve(peer, pieces, transfer).await {
+                            eprintln!("{} -> {}", peer_address, e);
+                        }
+                        None
+                    });
+                }
+                Ok(()) = announced.changed() => {
+                    let peer_addrs = announced.borrow_and_update().clone();
+                    for peer_address in peer_addrs {
+                        dial(&mut join_set, &mut dialled, peer_address);
+                    }
+                }
+                Some(result) = join_set.join_next() => {
+                    if let Ok(Some(peer_address)) = result {
+                        dialled.remove(&peer_address);
+                    }
+                }
+                result = tokio::signal::ctrl_c() => {
+                    result?;
+                    break;
+                }
+            }
+        }
+        join_set.abort_all();
+        println!(
+            "Uploaded {} bytes",
+            transfer.uploaded.load(Ordering::Relaxed)
+        );
+        announcer.stop().await
+    }
+
+    /// Downloads from `peer_addrs` and from whoever arrives on `incoming`,
+    /// which only ends early if we aren't `listening`.
+    /// Every connected peer is sent a `HAVE` for each piece that verifies.
+    async fn download_from(
+        &self,
+        peer_addrs: Vec<SocketAddr>,
+        mut incoming: mpsc::UnboundedReceiver<Peer>,
+        listening: bool,
+        output: &Path,
+        transfer: &Arc<Transfer>,
+    ) -> Result<()> {
         let piece_hashes = self.pieces();
         let num_pieces = piece_hashes.len();
-        let info_hash = self.info_hash()?;
-        let piece_len = self.info.piece_length;
-        let file_len = self.len();
+        let info_hash = self.info_hash();
+        let piece_lens = (0..num_pieces)
+            .map(|piece| self.info.piece_len(piece))
+            .collect::<Result<Vec<u32>>>()?;
+        let storage = Arc::new(Storage::new(&self.info, output)?);
+        storage.create()?;
+        // What we've got so far, offered to every peer we're connected to.
+        let local = Arc::new(LocalPieces::new(&self.info, output)?);
+        let mut uploads = JoinSet::new();
+        let upload = |uploads: &mut JoinSet<_>, peer: &Peer| {
+            let peer_address = peer.address;
+            let serving = seed::serve(peer.clone(), local.clone(), transfer.clone());
+            uploads.spawn(async move {
+                if let Err(e) = serving.await {
+                    eprintln!("{} -> {}", peer_address, e);
+                }
+            });
+        };
 
-        let mut peer_piece_map: HashMap<usize, Vec<Peer>> = HashMap::new();
+        // Every peer we're connected to: where pieces come from, and who
+        // hears about the ones we get.
+        let mut connected: Vec<Peer> = Vec::new();
         let mut join_set = JoinSet::new();
+        // Peers learning what they have. A leecher with nothing may send
+        // nothing until we do, so one that stays quiet is taken to have no
+        // pieces.
+        let mut joining = JoinSet::new();
+        let join = |joining: &mut JoinSet<_>, mut peer: Peer| {
+            joining.spawn(async move {
+                let pieces = timeout(JOIN_TIMEOUT, peer.get_pieces())
+                    .await
+                    .unwrap_or(Ok(vec![]));
+                (peer, pieces)
+            });
+        };
 
+        // Tracker peers are all connected to at once; one that doesn't
+        // answer in time, or fails, is left out.
+        let mut connecting = JoinSet::new();
         for peer_address in peer_addrs {
-            match Peer::new(peer_address, info_hash).await {
-                Ok(mut peer) => {
-                    let pieces = peer.get_pieces().await?;
-                    for piece in pieces {
-                        peer_piece_map
-                            .entry(piece)
-                            .or_insert_with(Vec::new)
-                            .push(peer.clone());
-                    }
-                    peer.prepare_download().await?;
+            connecting.spawn(async move {
+                let peer = timeout(JOIN_TIMEOUT, Peer::new(peer_address, info_hash))
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("handshake timed out")));
+                (peer_address, peer)
+            });
+        }
+        while let Some(connect_result) = connecting.join_next().await {
+            match connect_result.context("Task panicked")? {
+                (_, Ok(peer)) => {
+                    upload(&mut uploads, &peer);
+                    connected.push(peer.clone());
+                    join(&mut joining, peer);
                 }
-                Err(e) => eprintln!("{} -> {}", peer_address, e),
+                (peer_address, Err(e)) => eprintln!("{} -> {}", peer_address, e),
+            }
+        }
+        while let Some(join_result) = joining.join_next().await {
+            if let (peer, Err(e)) = join_result.context("Task panicked")? {
+                eprintln!("{} -> {}", peer.address, e);
             }
         }
+        connected.retain(|peer| !peer.is_closed());
 
-        if peer_piece_map.is_empty() {
+        if connected.is_empty() && !listening {
             return Err(anyhow!("Could not connect to any peers"));
         }
 
-        let choose_peer = |piece: usize| {
-            let peers = peer_piece_map.get(&piece).unwrap();
-            peers.choose(&mut rand::thread_rng()).unwrap().clone()
-        };
-
-        let spawn = |join_set: &mut JoinSet<_>, piece: usize| {
-            let mut peer = choose_peer(piece);
+        let mut picker = PiecePicker::new(num_pieces, self.info.piece_length);
+        let spawn = |join_set: &mut JoinSet<_>, peer: &Peer, piece: usize| {
+            let mut peer = peer.clone();
             let piece_hashes = piece_hashes.clone();
             let piece_number = piece + 1;
-            let piece_len = std::cmp::min(piece_len, file_len - piece as u32 * piece_len);
+            let piece_len = piece_lens[piece];
+            let transfer = transfer.clone();
 
             join_set.spawn(async move {
-                match peer.load_piece(piece as u32, piece_len).await {
+                let loaded = match timeout(JOIN_TIMEOUT, peer.prepare_download()).await {
+                    Ok(Ok(())) => peer.load_piece(piece as u32, piece_len).await,
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => Err(anyhow!("peer did not unchoke us")),
+                };
+                let data = match loaded {
                     Ok(data) => {
+                        transfer
+                            .downloaded
+                            .fetch_add(data.len() as u64, Ordering::Relaxed);
                         println!(
                             "Downloaded piece {}/{} from peer {}",
                             piece_number, num_pieces, peer.address
@@ -192,9 +605,9 @@ impl Torrent {
                                 "Piece {}/{} failed verification. Will retry...",
                                 piece_number, num_pieces
                             );
-                            (piece, vec![])
+                            vec![]
                         } else {
-                            (piece, data)
+                            data
                         }
                     }
                     Err(e) => {
@@ -202,29 +615,199 @@ impl Torrent {
                             "Error loading piece {}/{}: {}. Will retry...",
                             piece_number, num_pieces, e
                         );
-                        (piece, vec![])
+                        vec![]
                     }
-                }
+                };
+                (peer.address, piece, data)
             });
         };
+        // Gives `peers` as many pending pieces as they have room for.
+        let fill = |join_set: &mut JoinSet<_>, picker: &mut PiecePicker, peers: &[Peer]| {
+            for peer in peers.iter().filter(|peer| !peer.is_closed()) {
+                for piece in picker.pick(peer.address, |piece| peer.has_piece(piece)) {
+                    spawn(join_set, peer, piece);
+                }
+            }
+        };
 
-        for piece in 0..num_pieces {
-            spawn(&mut join_set, piece);
+        fill(&mut join_set, &mut picker, &connected);
+        let missing = picker
+            .pending()
+            .iter()
+            .filter(|&&piece| !connected.iter().any(|peer| peer.has_piece(piece)))
+            .count();
+        if missing > 0 {
+            println!("Waiting for peers with {} more pieces", missing);
         }
 
-        let mut file_bytes = vec![0u8; file_len as usize];
-        while let Some(join_result) = join_set.join_next().await {
-            let (piece, data) = join_result.context("Task panicked")?;
-            if data.is_empty() {
-                println!("Retrying piece {}/{}", piece + 1, num_pieces);
-                spawn(&mut join_set, piece);
-            } else {
-                let start = piece * piece_len as usize;
-                let end = start + data.len();
-                file_bytes[start..end].copy_from_slice(&data);
+        // Peers announce pieces as they get them, so pending pieces are
+        // offered again now and then while anyone is still connected.
+        let mut recheck = interval(WAITING_RECHECK);
+        let mut remaining = num_pieces;
+        while remaining > 0 {
+            let anyone = connected.iter().any(|peer| !peer.is_closed());
+            tokio::select! {
+                Some(join_result) = join_set.join_next() => {
+                    let (address, piece, data) = join_result.context("Task panicked")?;
+                    if data.is_empty() {
+                        println!("Retrying piece {}/{}", piece + 1, num_pieces);
+                        picker.finished(address, piece, false);
+                        fill(&mut join_set, &mut picker, &connected);
+                        continue;
+                    }
+                    let len = data.len() as u64;
+                    storage.write_async(self.info.piece_offset(piece)?, data).await?;
+                    local.add(piece);
+                    transfer.left.fetch_sub(len, Ordering::Relaxed);
+                    remaining -= 1;
+                    picker.finished(address, piece, true);
+                    // Peers that have gone away are dropped on the way.
+                    connected.retain(|peer| peer.try_send(Message::Have(piece as u32)).is_ok());
+                    // Only the peer that finished has room for more.
+                    let finished: Vec<Peer> = connected
+                        .iter()
+                        .filter(|peer| peer.address == address)
+                        .cloned()
+                        .collect();
+                    fill(&mut join_set, &mut picker, &finished);
+                }
+                Some(peer) = incoming.recv() => {
+                    upload(&mut uploads, &peer);
+                    connected.push(peer.clone());
+                    join(&mut joining, peer);
+                }
+                Some(join_result) = joining.join_next() => {
+                    match join_result.context("Task panicked")? {
+                        (peer, Ok(pieces)) => {
+                            println!("Peer {} connected with {} pieces", peer.address, pieces.len());
+                            fill(&mut join_set, &mut picker, &[peer]);
+                        }
+                        (peer, Err(e)) => eprintln!("{} -> {}", peer.address, e),
+                    }
+                }
+                _ = recheck.tick(), if anyone && !picker.pending().is_empty() => {
+                    fill(&mut join_set, &mut picker, &connected);
+                }
+                else => bail!("{} pieces are missing and no peer has them", remaining),
             }
         }
 
-        Ok(file_bytes)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_info_hash_of_sample_torrent() {
+        let torrent = Torrent::new(PathBuf::from("sample.torrent")).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+    }
+
+    #[test]
+    fn test_info_hash_covers_unmodelled_keys() {
+        let info =
+            b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        let content = [b"d8:announce3:url4:info".as_slice(), info, b"e"].concat();
+        let torrent = Torrent::from_bytes(&content).unwrap();
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(info)));
+    }
+
+    #[test]
+    fn test_deeply_nested_unknown_key_is_an_error() {
+        let depth = 50_000;
+        let content = [
+            b"d8:announce3:url4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae3:x-z".as_slice(),
+            &vec![b'l'; depth],
+            &vec![b'e'; depth],
+            b"e",
+        ]
+        .concat();
+        assert!(Torrent::from_bytes(&content).is_err());
+    }
+
+    #[test]
+    fn test_round_trip_keeps_unknown_keys() {
+        let content = b"d8:announce3:url13:announce-listll3:urlel4:url2ee7:comment2:hi10:created by2:me13:creation datei1700000000e4:infod5:filesld6:lengthi2e6:md5sum32:0123456789abcdef0123456789abcdef4:pathl1:aeee4:name3:dir12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source2:CIe5:x-tagi7ee";
+        let torrent = Torrent::from_bytes(content).unwrap();
+        assert_eq!(torrent.comment, Some(ByteBuf::from("hi")));
+        assert_eq!(torrent.info.private, Some(1));
+        assert_eq!(torrent.info.source, Some(ByteBuf::from("CI")));
+        assert_eq!(
+            torrent.extra.get(&ByteBuf::from("x-tag")),
+            Some(&BencodedValue::Int(7))
+        );
+        assert!(torrent.info.extra.is_empty());
+        assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), content);
+    }
+
+    #[test]
+    fn test_round_trip_keeps_non_utf8_metadata() {
+        let content = b"d8:announce3:url7:comment2:\xff\xfe4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source1:\xe93:x-\x80i1ee2:\xc3\x28i2ee";
+        let torrent = Torrent::from_bytes(content).unwrap();
+        assert_eq!(torrent.comment, Some(ByteBuf::from(b"\xff\xfe".to_vec())));
+        assert_eq!(torrent.info.source, Some(ByteBuf::from(b"\xe9".to_vec())));
+        assert!(torrent
+            .extra
+            .contains_key(&ByteBuf::from(b"\xc3\x28".to_vec())));
+        assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), content);
+    }
+
+    #[test]
+    fn test_round_trip_single_file_with_url_list() {
+        let content = b"d8:announce3:url4:infod4:attr1:x6:lengthi3e4:name1:a12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list9:http://see";
+        let torrent = Torrent::from_bytes(content).unwrap();
+        assert_eq!(torrent.url_list.as_ref().unwrap().urls(), ["http://se"]);
+        assert_eq!(
+            torrent.info.extra.keys().collect::<Vec<_>>(),
+            [&ByteBuf::from("attr")]
+        );
+        assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), content);
+    }
+
+    #[test]
+    fn test_trackers_are_built_once() {
+        let content = b"d8:announce3:url13:announce-listll1:a1:b1:cel1:dee4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = Torrent::from_bytes(content).unwrap();
+        assert_eq!(torrent.tracker_urls(), ["a", "b", "c", "d"]);
+        assert!(Arc::ptr_eq(&torrent.trackers(), &torrent.trackers()));
+        assert!(Arc::ptr_eq(
+            &torrent.trackers(),
+            &torrent.clone().trackers()
+        ));
+    }
+
+    #[test]
+    fn test_piece_math_beyond_4_gib() {
+        let length = 5 * 1024 * 1024 * 1024 + 100; // 5 GiB + 100 bytes
+        let info = Info::single_file("big".to_string(), length, 256 * 1024);
+        let last_piece = 5 * 4096;
+
+        assert_eq!(info.file_len(), length);
+        assert_eq!(info.piece_offset(last_piece).unwrap(), 5 << 30);
+        assert_eq!(info.piece_len(last_piece - 1).unwrap(), 256 * 1024);
+        assert_eq!(info.piece_len(last_piece).unwrap(), 100);
+        assert!(info.piece_len(last_piece + 1).is_err());
+        assert!(info.piece_offset(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_multi_file_lengths_beyond_4_gib() {
+        let content = b"d8:announce3:url4:infod5:filesld6:lengthi4294967296e4:pathl1:aeed6:lengthi6000000000e4:pathl1:beee4:name3:dir12:piece lengthi1048576e6:pieces0:ee";
+        let torrent = Torrent::from_bytes(content).unwrap();
+        assert_eq!(torrent.len(), 4294967296 + 6000000000);
+        assert_eq!(serde_bencode::to_bytes(&torrent).unwrap(), content);
+
+        let max = "d6:lengthi9223372036854775807e4:pathl1:aee";
+        let overflowing = format!(
+            "d8:announce3:url4:infod5:filesl{max}{max}{max}e4:name3:dir12:piece lengthi1e6:pieces0:ee"
+        );
+        let err = Torrent::from_bytes(overflowing.as_bytes()).err().unwrap();
+        assert!(err.to_string().contains("overflows"), "{}", err);
     }
 }
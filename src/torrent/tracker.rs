@@ -1,47 +1,783 @@
-use crate::torrent::peer::Peer;
+use crate::torrent::{
+    listener,
+    parser::{self, Limits, NodeValue, ParseOptions},
+    peer_id::PeerId,
+    udp_tracker::{self, UdpTracker, MAX_SCRAPE_HASHES},
+};
+use anyhow::{anyhow, bail, Result};
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use serde_bytes::ByteBuf;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+    time::Duration,
+};
+use thiserror::Error;
+use url::form_urlencoded;
+
+/// How long an HTTP tracker gets to accept a connection, and to answer in
+/// full.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shared by every HTTP announce and scrape, so a tracker that never answers
+/// is given up on and the next one tried.
+static HTTP_CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("failed to build HTTP client")
+});
 
 #[derive(Debug, Serialize)]
 pub struct TrackerRequest {
-    peer_id: String,
-    port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: u32,
-    compact: u8,
+    /// Raw bytes, so appended to the query by hand like the info hash.
+    #[serde(skip)]
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// BEP 7: our global IPv6 address, so a tracker reached over IPv4 can
+    /// still hand us out to v6 peers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+/// Our global IPv6 address, if the host has one: the source address the OS
+/// would pick to reach a public v6 host. Connecting a UDP socket sends
+/// nothing.
+static LOCAL_IPV6: LazyLock<Option<Ipv6Addr>> = LazyLock::new(|| {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    let public = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+    socket.connect((public, 53)).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        ip @ IpAddr::V6(v6) if is_global(ip) => Some(v6),
+        _ => None,
+    }
+});
+
+/// Whether peers elsewhere on the internet could reach `ip`: not private,
+/// local, multicast or set aside for documentation and benchmarks.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && b & 0xc0 == 64) // shared address space
+                || (a == 198 && b & 0xfe == 18) // benchmarking
+                || (a == 192 && b == 0 && c == 0)) // protocol assignments
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            match ip.to_ipv4_mapped() {
+                Some(v4) => is_global(v4.into()),
+                None => {
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_multicast()
+                        || ip.is_unicast_link_local()
+                        || ip.is_unique_local()
+                        || segments[..4] == [0x100, 0, 0, 0] // discard-only
+                        || segments[..2] == [0x2001, 0xdb8] // documentation
+                        || (segments[0] == 0x3fff && segments[1] < 0x1000) // documentation
+                        || segments[..3] == [0x2001, 2, 0]) // benchmarking
+                }
+            }
+        }
+    }
+}
+
+/// Why an announce is sent; regular re-announces carry no event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl FromStr for Event {
+    type Err = anyhow::Error;
+
+    fn from_str(event: &str) -> Result<Self> {
+        match event {
+            "started" => Ok(Self::Started),
+            "completed" => Ok(Self::Completed),
+            "stopped" => Ok(Self::Stopped),
+            _ => bail!("unknown event {:?}", event),
+        }
+    }
+}
+
+impl Event {
+    /// The BEP 15 encoding, where 0 means no event.
+    pub fn udp_code(event: Option<Self>) -> u32 {
+        match event {
+            None => 0,
+            Some(Self::Completed) => 1,
+            Some(Self::Started) => 2,
+            Some(Self::Stopped) => 3,
+        }
+    }
+
+    /// Decodes [`Event::udp_code`]; `None` for codes BEP 15 doesn't define.
+    pub fn from_udp_code(code: u32) -> Option<Option<Self>> {
+        match code {
+            0 => Some(None),
+            1 => Some(Some(Self::Completed)),
+            2 => Some(Some(Self::Started)),
+            3 => Some(Some(Self::Stopped)),
+            _ => None,
+        }
+    }
 }
 
 impl TrackerRequest {
-    pub fn new(left: u32) -> Self {
-        let peer_id = Peer::gen_peer_id();
+    pub fn new(left: u64) -> Self {
         Self {
-            peer_id,
-            port: 6881,
+            peer_id: PeerId::session(),
+            port: listener::port(),
             uploaded: 0,
             downloaded: 0,
             left,
             compact: 1,
+            event: None,
+            ipv6: *LOCAL_IPV6,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker refused the request and said why.
+    #[error("tracker error: {0}")]
+    Failure(String),
+    #[error("tracker replied with HTTP {0}")]
+    Status(reqwest::StatusCode),
+    #[error("tracker response exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("malformed tracker response: {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct TrackerResponse {
-    interval: Option<u32>,
-    #[serde(with = "serde_bytes")]
-    peers: Vec<u8>,
+    #[serde(
+        rename = "failure reason",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub failure_reason: Option<String>,
+    #[serde(
+        rename = "warning message",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub warning_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u32>,
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_interval: Option<u32>,
+    /// To be sent back as `trackerid` on later announces.
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<ByteBuf>,
+    /// Number of seeders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<u32>,
+    /// Number of leechers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<u32>,
+    #[serde(default)]
+    pub peers: Peers,
+    /// Compact IPv6 peers, 18 bytes each.
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
+    pub peers6: Vec<u8>,
+}
+
+/// Trackers send 6-byte compact entries when they honour `compact=1`, and a
+/// list of dicts otherwise.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Peers {
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    Dicts(Vec<PeerEntry>),
+}
+
+impl Default for Peers {
+    fn default() -> Self {
+        Self::Compact(vec![])
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerEntry {
+    #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<ByteBuf>,
+    pub ip: String,
+    pub port: u16,
 }
 
 impl TrackerResponse {
+    /// Reads and decodes an HTTP announce response, bounding both the body
+    /// size and the bencode structure.
+    pub async fn from_http(mut response: reqwest::Response) -> Result<Self, TrackerError> {
+        let status = response.status();
+        let body = read_body(&mut response).await?;
+        match Self::from_bytes(&body) {
+            Err(TrackerError::Malformed(_)) | Ok(_) if !status.is_success() => {
+                Err(TrackerError::Status(status))
+            }
+            result => result,
+        }
+    }
+
+    /// Decodes an announce response, turning `failure reason` into an error.
+    pub fn from_bytes(body: &[u8]) -> Result<Self, TrackerError> {
+        let response: Self = parser::from_bytes_limited(body, Limits::default())
+            .map_err(|e| TrackerError::Malformed(e.to_string()))?;
+        match response.failure_reason {
+            Some(reason) => Err(TrackerError::Failure(reason)),
+            None => Ok(response),
+        }
+    }
+
+    /// Every peer given as an IP address; dict entries naming a host are
+    /// skipped.
     pub fn peers(&self) -> Vec<SocketAddr> {
-        self.peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
-                let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-                SocketAddr::new(ip, port)
+        let v4: Vec<SocketAddr> = match &self.peers {
+            Peers::Compact(peers) => peers
+                .chunks_exact(6)
+                .map(|chunk| {
+                    let ip = IpAddr::V4(Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]));
+                    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+                    SocketAddr::new(ip, port)
+                })
+                .collect(),
+            Peers::Dicts(entries) => entries
+                .iter()
+                .filter_map(|entry| Some(SocketAddr::new(entry.ip.parse().ok()?, entry.port)))
+                .collect(),
+        };
+        let v6 = self.peers6.chunks_exact(18).map(|chunk| {
+            let ip = IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap()));
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::new(ip, port)
+        });
+        v4.into_iter().chain(v6).collect()
+    }
+}
+
+/// Sends `request` to the tracker at `url`, echoing the `tracker id` it
+/// handed out earlier, if any.
+pub async fn announce(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+    tracker_id: Option<&[u8]>,
+) -> Result<TrackerResponse> {
+    announce_with_retries(
+        url,
+        info_hash,
+        request,
+        tracker_id,
+        udp_tracker::MAX_RETRIES,
+    )
+    .await
+}
+
+/// Like [`announce`], retransmitting to a UDP tracker at most `udp_retries`
+/// times.
+async fn announce_with_retries(
+    url: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+    tracker_id: Option<&[u8]>,
+    udp_retries: u32,
+) -> Result<TrackerResponse> {
+    if url.starts_with("http") {
+        let params = serde_urlencoded::to_string(request)?;
+        let info_hash_str: String = form_urlencoded::byte_serialize(info_hash).collect();
+        let peer_id_str: String = form_urlencoded::byte_serialize(&request.peer_id.0).collect();
+        let mut url = format!(
+            "{}?{}&info_hash={}&peer_id={}",
+            url, params, info_hash_str, peer_id_str
+        );
+        if let Some(tracker_id) = tracker_id {
+            url.push_str("&trackerid=");
+            url.extend(form_urlencoded::byte_serialize(tracker_id));
+        }
+        let response = HTTP_CLIENT.get(url).send().await?;
+        Ok(TrackerResponse::from_http(response).await?)
+    } else if url.starts_with("udp") {
+        UdpTracker::new(url)
+            .await?
+            .with_max_retries(udp_retries)
+            .announce(info_hash, request)
+            .await
+    } else {
+        bail!("Unsupported tracker protocol")
+    }
+}
+
+/// Reads an HTTP tracker's response body, up to the default size limit.
+async fn read_body(response: &mut reqwest::Response) -> Result<Vec<u8>, TrackerError> {
+    let max_total_bytes = Limits::default().max_total_bytes;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_total_bytes {
+            return Err(TrackerError::TooLarge(max_total_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Swarm counts for one info hash, as reported by a scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// Asks the tracker at `url` for the swarm counts of `info_hashes`, batching
+/// them into as few requests as the protocol allows. Hashes the tracker
+/// doesn't know are left out.
+pub async fn scrape(url: &str, info_hashes: &[[u8; 20]]) -> Result<HashMap<[u8; 20], ScrapeStats>> {
+    let mut stats = HashMap::new();
+    if url.starts_with("http") {
+        let scrape_url = scrape_url(url).ok_or(anyhow!("{} does not support scrape", url))?;
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let query = batch
+                .iter()
+                .map(|info_hash| {
+                    let info_hash: String = form_urlencoded::byte_serialize(info_hash).collect();
+                    format!("info_hash={}", info_hash)
+                })
+                .collect::<Vec<_>>()
+                .join("&");
+            let separator = if scrape_url.contains('?') { '&' } else { '?' };
+            let mut response = HTTP_CLIENT
+                .get(format!("{}{}{}", scrape_url, separator, query))
+                .send()
+                .await?;
+            let status = response.status();
+            let body = read_body(&mut response).await?;
+            match parse_scrape(&body) {
+                Ok(files) => stats.extend(files),
+                Err(TrackerError::Malformed(_)) if !status.is_success() => {
+                    return Err(TrackerError::Status(status).into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    } else if url.starts_with("udp") {
+        let tracker = UdpTracker::new(url)
+            .await?
+            .with_max_retries(FAILOVER_UDP_RETRIES);
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let batch_stats = tracker.scrape(batch).await?;
+            stats.extend(batch.iter().copied().zip(batch_stats));
+        }
+    } else {
+        bail!("Unsupported tracker protocol")
+    }
+    Ok(stats)
+}
+
+/// By convention an HTTP tracker scrapes at its announce URL with the last
+/// path segment's `announce` replaced by `scrape`; trackers whose URL doesn't
+/// follow the pattern don't support scraping.
+fn scrape_url(announce: &str) -> Option<String> {
+    let slash = announce.rfind('/')?;
+    let (base, last) = announce.split_at(slash + 1);
+    let rest = last.strip_prefix("announce")?;
+    Some(format!("{}scrape{}", base, rest))
+}
+
+/// Decodes the `files` dict of an HTTP scrape response, which is keyed by
+/// raw info hash and so can't go through serde.
+fn parse_scrape(body: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let options = ParseOptions {
+        limits: Some(Limits::default()),
+        ..Default::default()
+    };
+    let root =
+        parser::parse_with(body, &options).map_err(|e| TrackerError::Malformed(e.to_string()))?;
+    if let Some(NodeValue::Bytes(reason)) = root.get(b"failure reason").map(|node| &node.value) {
+        return Err(TrackerError::Failure(
+            String::from_utf8_lossy(reason).into_owned(),
+        ));
+    }
+    let Some(NodeValue::Dict(files)) = root.get(b"files").map(|node| &node.value) else {
+        return Err(TrackerError::Malformed(
+            "missing files dictionary".to_string(),
+        ));
+    };
+
+    let count = |node: &parser::Node, key: &[u8]| match node.get(key).map(|n| &n.value) {
+        Some(&NodeValue::Int(n)) => u32::try_from(n).unwrap_or_default(),
+        _ => 0,
+    };
+    let stats = files
+        .iter()
+        .filter_map(|(info_hash, file)| {
+            let stats = ScrapeStats {
+                seeders: count(file, b"complete"),
+                completed: count(file, b"downloaded"),
+                leechers: count(file, b"incomplete"),
+            };
+            Some((<[u8; 20]>::try_from(*info_hash).ok()?, stats))
+        })
+        .collect();
+    Ok(stats)
+}
+
+/// The merged outcome of announcing to every tier.
+#[derive(Debug, Default)]
+pub struct Announce {
+    pub peers: Vec<SocketAddr>,
+    /// The longest re-announce interval any responding tracker asked for.
+    pub interval: Option<u32>,
+    pub min_interval: Option<u32>,
+}
+
+/// Retransmissions per UDP tracker when failing over or scraping: the first
+/// two attempts take 45 seconds, and BEP 15's full eight would take over an
+/// hour before the next tracker got a turn.
+const FAILOVER_UDP_RETRIES: u32 = 1;
+
+/// The BEP 12 tracker tiers of a torrent. Trackers are shuffled within each
+/// tier once, and a tracker that answers is moved to the front of its tier so
+/// later announces try it first.
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    tracker_ids: HashMap<String, Vec<u8>>,
+}
+
+impl TrackerTiers {
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .collect::<Vec<_>>();
+        for tier in &mut tiers {
+            tier.shuffle(&mut rand::thread_rng());
+        }
+        Self {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    /// Announces to the first working tracker of every tier and merges the
+    /// peers they return. Fails only if no tracker at all answered.
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<Announce> {
+        self.announce_with(|url, tracker_id| async move {
+            let tracker_id = tracker_id.as_deref();
+            announce_with_retries(&url, info_hash, request, tracker_id, FAILOVER_UDP_RETRIES).await
+        })
+        .await
+    }
+
+    async fn announce_with<F, Fut>(&mut self, mut announce: F) -> Result<Announce>
+    where
+        F: FnMut(String, Option<Vec<u8>>) -> Fut,
+        Fut: Future<Output = Result<TrackerResponse>>,
+    {
+        let mut merged = Announce::default();
+        let mut answered = false;
+        let mut last_error = None;
+        for tier in &mut self.tiers {
+            for i in 0..tier.len() {
+                let tracker_id = self.tracker_ids.get(&tier[i]).cloned();
+                match announce(tier[i].clone(), tracker_id).await {
+                    Ok(response) => {
+                        if let Some(warning) = &response.warning_message {
+                            eprintln!("{} warning: {}", tier[i], warning);
+                        }
+                        if let Some(tracker_id) = response.tracker_id.clone() {
+                            self.tracker_ids
+                                .insert(tier[i].clone(), tracker_id.into_vec());
+                        }
+                        let url = tier.remove(i);
+                        tier.insert(0, url);
+                        for peer in response.peers() {
+                            if !merged.peers.contains(&peer) {
+                                merged.peers.push(peer);
+                            }
+                        }
+                        merged.interval = merged.interval.max(response.interval);
+                        merged.min_interval = merged.min_interval.max(response.min_interval);
+                        answered = true;
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("{} -> {}", tier[i], e);
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        if !answered {
+            return Err(last_error.unwrap_or(anyhow!("torrent has no trackers")));
+        }
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(peers: &[[u8; 6]]) -> TrackerResponse {
+        TrackerResponse {
+            interval: Some(peers.len() as u32),
+            peers: Peers::Compact(peers.concat()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fail_over_promote_and_merge() {
+        let mut tiers = TrackerTiers::new(vec![
+            vec!["dead1".to_string(), "dead2".to_string(), "live".to_string()],
+            vec![],
+            vec!["second".to_string()],
+        ]);
+        assert_eq!(tiers.tiers.len(), 2);
+
+        let mut attempts = Vec::new();
+        let announced = tiers
+            .announce_with(|url, _| {
+                attempts.push(url.clone());
+                async move {
+                    match url.as_str() {
+                        "live" => Ok(response(&[[10, 0, 0, 1, 0, 80], [10, 0, 0, 2, 0, 80]])),
+                        "second" => Ok(response(&[[10, 0, 0, 2, 0, 80], [10, 0, 0, 3, 0, 80]])),
+                        _ => Err(anyhow!("connection refused")),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(announced.interval, Some(2));
+        let ips = announced
+            .peers
+            .iter()
+            .map(|peer| peer.ip().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(ips, ["10.0.0.1", "10.0.0.2", "10.0.0.3"]);
+        assert_eq!(tiers.tiers[0][0], "live");
+        assert_eq!(attempts.last().unwrap(), "second");
+
+        // The promoted tracker is asked first next time.
+        let mut attempts = Vec::new();
+        tiers
+            .announce_with(|url, _| {
+                attempts.push(url);
+                async { Ok(response(&[])) }
             })
-            .collect()
+            .await
+            .unwrap();
+        assert_eq!(attempts, ["live", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_tiers_fail_when_no_tracker_answers() {
+        let mut tiers = TrackerTiers::new(vec![vec!["a".to_string(), "b".to_string()]]);
+        let result = tiers
+            .announce_with(|_, _| async { Err(anyhow!("timed out")) })
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_failure_reason_is_an_error() {
+        let error = TrackerResponse::from_bytes(b"d14:failure reason12:unregisterede").unwrap_err();
+        assert!(matches!(&error, TrackerError::Failure(reason) if reason == "unregistered"));
+
+        let error = TrackerResponse::from_bytes(b"d8:intervali").unwrap_err();
+        assert!(matches!(error, TrackerError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_dict_peers_and_counts() {
+        let body = b"d8:completei5e10:incompletei7e8:intervali900e5:peersl\
+            d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+            d2:ip3:::14:porti51413ee\
+            d2:ip11:example.org4:porti80eee\
+            15:warning message4:slowe";
+        let response = TrackerResponse::from_bytes(body).unwrap();
+        assert_eq!((response.complete, response.incomplete), (Some(5), Some(7)));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert_eq!(
+            response.peers(),
+            [
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracker_id_is_echoed() {
+        let mut tiers = TrackerTiers::new(vec![vec!["t".to_string()]]);
+        let mut seen = Vec::new();
+        for _ in 0..2 {
+            tiers
+                .announce_with(|_, tracker_id| {
+                    seen.push(tracker_id);
+                    async {
+                        TrackerResponse::from_bytes(b"d5:peers0:10:tracker id3:abce")
+                            .map_err(Into::into)
+                    }
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(seen, [None, Some(b"abc".to_vec())]);
+    }
+
+    #[test]
+    fn test_compact_peers6() {
+        let mut body = b"d5:peers6:\x0a\x00\x00\x01\x00\x506:peers636:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(&[0x1a, 0xe1]);
+        body.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0, 80]);
+        body.push(b'e');
+        let response = TrackerResponse::from_bytes(&body).unwrap();
+        assert_eq!(
+            response.peers(),
+            [
+                "10.0.0.1:80".parse().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+                "[2001:db8::2]:80".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_announce_parameter() {
+        let mut request = TrackerRequest::new(0);
+        request.ipv6 = Some("2001:db8::1".parse().unwrap());
+        let query = serde_urlencoded::to_string(&request).unwrap();
+        assert!(query.ends_with("&ipv6=2001%3Adb8%3A%3A1"), "{}", query);
+
+        request.ipv6 = None;
+        let query = serde_urlencoded::to_string(&request).unwrap();
+        assert!(!query.contains("ipv6"));
+    }
+
+    #[test]
+    fn test_is_global() {
+        for ip in [
+            "2a00:1450:4001:80b::200e",
+            "3fff:1000::1",
+            "8.8.8.8",
+            "::ffff:1.1.1.1",
+        ] {
+            assert!(is_global(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "::1",
+            "::",
+            "fe80::1",
+            "fd00::1",
+            "ff02::1",
+            "2001:db8::1",
+            "3fff::1",
+            "3fff:fff::1",
+            "100::1",
+            "2001:2::1",
+            "::ffff:192.168.1.1",
+            "::ffff:192.0.2.1",
+            "192.0.2.1",
+            "198.51.100.1",
+            "203.0.113.1",
+            "10.0.0.1",
+            "100.64.0.1",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_global(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_scrape_url() {
+        let cases = [
+            ("http://t.example/announce", Some("http://t.example/scrape")),
+            (
+                "http://t.example/x/announce.php",
+                Some("http://t.example/x/scrape.php"),
+            ),
+            (
+                "http://t.example/announce?passkey=1",
+                Some("http://t.example/scrape?passkey=1"),
+            ),
+            ("http://t.example/a", None),
+            ("http://t.example/announce/x", None),
+        ];
+        for (announce, scrape) in cases {
+            assert_eq!(scrape_url(announce).as_deref(), scrape, "{}", announce);
+        }
+    }
+
+    #[test]
+    fn test_parse_scrape() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xaa; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10ee3:bad");
+        body.extend_from_slice(b"d8:completei1eeee");
+        let stats = parse_scrape(&body).unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                [0xaa; 20],
+                ScrapeStats {
+                    seeders: 5,
+                    completed: 50,
+                    leechers: 10
+                }
+            )])
+        );
+
+        let error = parse_scrape(b"d14:failure reason7:privatee").unwrap_err();
+        assert!(matches!(error, TrackerError::Failure(reason) if reason == "private"));
     }
 }
@@ -0,0 +1,934 @@
+use crate::torrent::{
+    encode::encode_value,
+    peer_id::PeerId,
+    tracker::{Event, PeerEntry, Peers, ScrapeStats, TrackerRequest, TrackerResponse},
+    udp_tracker::{
+        ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, MAX_SCRAPE_HASHES,
+        PROTOCOL_ID,
+    },
+};
+use anyhow::{anyhow, Context, Result};
+use serde_bencode::value::Value as BencodedValue;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    time::{interval, timeout},
+};
+
+/// Peers handed out per announce unless the client asks for fewer.
+const DEFAULT_NUM_WANT: usize = 50;
+/// The longest request head we read before giving up on a client.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// How long a client gets to send its request head.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct ServerConfig {
+    /// How often clients are told to re-announce.
+    pub interval: Duration,
+    /// Peers that haven't announced for this long are dropped.
+    pub peer_timeout: Duration,
+    /// When set, only these info hashes are tracked.
+    pub allow_list: Option<HashSet<[u8; 20]>>,
+}
+
+impl ServerConfig {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            peer_timeout: interval * 2,
+            allow_list: None,
+        }
+    }
+}
+
+/// Reads an allow-list file: one hex info hash per line, `#` comments and
+/// blank lines ignored.
+pub fn read_allow_list(path: &Path) -> Result<HashSet<[u8; 20]>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            hex::decode(line)?
+                .try_into()
+                .map_err(|_| anyhow!("{:?} is not a 20-byte info hash", line))
+        })
+        .collect()
+}
+
+/// An announce as the tracker sees it.
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub request: TrackerRequest,
+    pub num_want: Option<usize>,
+    /// Where the announce came from; its IP is what other peers dial.
+    pub remote: SocketAddr,
+}
+
+struct SwarmPeer {
+    addrs: Vec<SocketAddr>,
+    left: u64,
+    last_seen: Instant,
+    /// Whether this peer's download was counted in `Swarm::completed`.
+    completed: bool,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<PeerId, SwarmPeer>,
+    completed: u32,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let seeders = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+        ScrapeStats {
+            seeders,
+            completed: self.completed,
+            leechers: self.peers.len() as u32 - seeders,
+        }
+    }
+}
+
+/// Per-info-hash swarm state shared by every tracker front end.
+pub struct Swarms {
+    config: ServerConfig,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+impl Swarms {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records the announcing peer and returns others from its swarm, or the
+    /// reason it was refused.
+    pub fn announce(&self, announce: &AnnounceRequest) -> Result<TrackerResponse, String> {
+        if let Some(allow_list) = &self.config.allow_list {
+            if !allow_list.contains(&announce.info_hash) {
+                return Err("unregistered torrent".to_string());
+            }
+        }
+        let request = &announce.request;
+        if request.port == 0 {
+            return Err("invalid port".to_string());
+        }
+
+        let num_want = announce.num_want.unwrap_or(DEFAULT_NUM_WANT);
+        let mut swarms = self.swarms.lock().unwrap();
+        if request.event == Some(Event::Stopped) && !swarms.contains_key(&announce.info_hash) {
+            // Leaving a swarm we never knew of; no need to start one.
+            return Ok(self.response(&Swarm::default(), request, num_want));
+        }
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        self.expire(swarm);
+        if request.event == Some(Event::Stopped) {
+            swarm.peers.remove(&request.peer_id);
+        } else {
+            let mut addrs = vec![SocketAddr::new(announce.remote.ip(), request.port)];
+            if let Some(ipv6) = request.ipv6 {
+                addrs.push(SocketAddr::new(IpAddr::V6(ipv6), request.port));
+            }
+            // A download counts once: when the peer first says it finished,
+            // or is first seen going from leeching to seeding.
+            let previous = swarm.peers.get(&request.peer_id);
+            let mut completed = previous.is_some_and(|peer| peer.completed);
+            let finished = request.event == Some(Event::Completed)
+                || (request.left == 0 && previous.is_some_and(|peer| peer.left > 0));
+            if finished && !completed {
+                swarm.completed += 1;
+                completed = true;
+            }
+            let peer = SwarmPeer {
+                addrs,
+                left: request.left,
+                last_seen: Instant::now(),
+                completed,
+            };
+            swarm.peers.insert(request.peer_id, peer);
+        }
+
+        let response = self.response(swarm, request, num_want);
+        if swarm.peers.is_empty() {
+            swarms.remove(&announce.info_hash);
+        }
+        Ok(response)
+    }
+
+    /// Up to `num_want` peers from `swarm` other than the one asking, in the
+    /// form its request asked for.
+    fn response(
+        &self,
+        swarm: &Swarm,
+        request: &TrackerRequest,
+        num_want: usize,
+    ) -> TrackerResponse {
+        let others = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != request.peer_id)
+            .take(num_want);
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut entries = Vec::new();
+        for (peer_id, peer) in others {
+            for addr in &peer.addrs {
+                match addr {
+                    SocketAddr::V4(addr) => {
+                        v4.extend_from_slice(&addr.ip().octets());
+                        v4.extend_from_slice(&addr.port().to_be_bytes());
+                    }
+                    SocketAddr::V6(addr) => {
+                        v6.extend_from_slice(&addr.ip().octets());
+                        v6.extend_from_slice(&addr.port().to_be_bytes());
+                    }
+                }
+                entries.push(PeerEntry {
+                    peer_id: Some(ByteBuf::from(peer_id.0.to_vec())),
+                    ip: addr.ip().to_string(),
+                    port: addr.port(),
+                });
+            }
+        }
+
+        let stats = swarm.stats();
+        let (peers, peers6) = if request.compact == 1 {
+            (Peers::Compact(v4), v6)
+        } else {
+            (Peers::Dicts(entries), vec![])
+        };
+        TrackerResponse {
+            interval: Some(self.config.interval.as_secs() as u32),
+            complete: Some(stats.seeders),
+            incomplete: Some(stats.leechers),
+            peers,
+            peers6,
+            ..Default::default()
+        }
+    }
+
+    /// Counts for the given swarms, or for every swarm when none are named.
+    /// Unknown info hashes are left out.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], ScrapeStats)> {
+        if info_hashes.is_empty() {
+            self.sweep();
+            let swarms = self.swarms.lock().unwrap();
+            return swarms
+                .iter()
+                .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+                .collect();
+        }
+        let mut swarms = self.swarms.lock().unwrap();
+        info_hashes
+            .iter()
+            .filter_map(|info_hash| {
+                let swarm = swarms.get_mut(info_hash)?;
+                self.expire(swarm);
+                if swarm.peers.is_empty() {
+                    swarms.remove(info_hash);
+                    return None;
+                }
+                Some((*info_hash, swarm.stats()))
+            })
+            .collect()
+    }
+
+    /// Drops expired peers from every swarm, and the swarms left empty.
+    pub fn sweep(&self) {
+        self.swarms.lock().unwrap().retain(|_, swarm| {
+            self.expire(swarm);
+            !swarm.peers.is_empty()
+        });
+    }
+
+    fn expire(&self, swarm: &mut Swarm) {
+        let timeout = self.config.peer_timeout;
+        swarm
+            .peers
+            .retain(|_, peer| peer.last_seen.elapsed() < timeout);
+    }
+}
+
+/// Sweeps `swarms` once every peer timeout, so swarms nobody announces to
+/// or scrapes are still freed.
+pub async fn sweep_swarms(swarms: Arc<Swarms>) {
+    // A zero period would make `interval` panic.
+    let mut ticker = interval(swarms.config.peer_timeout.max(Duration::from_secs(1)));
+    loop {
+        ticker.tick().await;
+        swarms.sweep();
+    }
+}
+
+/// Serves `/announce` and `/scrape` over HTTP until the listener fails.
+pub async fn serve_http(listener: TcpListener, swarms: Arc<Swarms>) -> Result<()> {
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let swarms = swarms.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, remote, &swarms).await {
+                eprintln!("{} -> {}", remote, e);
+            }
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, remote: SocketAddr, swarms: &Swarms) -> Result<()> {
+    let head = timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .context("timed out reading request")??;
+    let head = String::from_utf8_lossy(&head);
+    let target = match head.split(' ').collect::<Vec<_>>()[..] {
+        ["GET", target, ..] => target,
+        _ => return respond(&mut stream, "405 Method Not Allowed", b"").await,
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = parse_query(query);
+
+    let body = match path {
+        "/announce" => match parse_announce(&params, remote) {
+            Ok(announce) => match swarms.announce(&announce) {
+                Ok(response) => serde_bencode::to_bytes(&response)?,
+                Err(reason) => failure(&reason),
+            },
+            Err(reason) => failure(&reason),
+        },
+        "/scrape" => {
+            let info_hashes = params
+                .iter()
+                .filter(|(key, _)| key == "info_hash")
+                .map(|(_, value)| <[u8; 20]>::try_from(value.as_slice()))
+                .collect::<Result<Vec<_>, _>>();
+            match info_hashes {
+                Ok(info_hashes) => scrape_body(&swarms.scrape(&info_hashes)),
+                Err(_) => failure("invalid info_hash"),
+            }
+        }
+        _ => return respond(&mut stream, "404 Not Found", b"").await,
+    };
+    respond(&mut stream, "200 OK", &body).await
+}
+
+/// Reads up to the blank line ending the request head.
+async fn read_head(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        anyhow::ensure!(head.len() < MAX_REQUEST_LEN, "request head too long");
+        let len = stream.read(&mut buf).await?;
+        anyhow::ensure!(len > 0, "connection closed mid-request");
+        head.extend_from_slice(&buf[..len]);
+    }
+    Ok(head)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &[u8]) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    Ok(())
+}
+
+/// A response holding nothing but `failure reason`, as BEP 3 prescribes.
+fn failure(reason: &str) -> Vec<u8> {
+    let reason = BencodedValue::Bytes(reason.as_bytes().to_vec());
+    encode_value(&BencodedValue::Dict(HashMap::from([(
+        b"failure reason".to_vec(),
+        reason,
+    )])))
+}
+
+/// The `files` dict is keyed by raw info hash, so it is built as a value
+/// rather than through serde.
+fn scrape_body(stats: &[([u8; 20], ScrapeStats)]) -> Vec<u8> {
+    let int = |n: u32| BencodedValue::Int(n as i64);
+    let files = stats
+        .iter()
+        .map(|(info_hash, stats)| {
+            let file = HashMap::from([
+                (b"complete".to_vec(), int(stats.seeders)),
+                (b"downloaded".to_vec(), int(stats.completed)),
+                (b"incomplete".to_vec(), int(stats.leechers)),
+            ]);
+            (info_hash.to_vec(), BencodedValue::Dict(file))
+        })
+        .collect();
+    let root = HashMap::from([(b"files".to_vec(), BencodedValue::Dict(files))]);
+    encode_value(&BencodedValue::Dict(root))
+}
+
+/// Splits a query string into percent-decoded pairs, keeping values as raw
+/// bytes since `info_hash` and `peer_id` are binary.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = String::from_utf8_lossy(&percent_decode(key)).into_owned();
+            (key, percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+fn parse_announce(
+    params: &[(String, Vec<u8>)],
+    remote: SocketAddr,
+) -> Result<AnnounceRequest, String> {
+    let param = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_slice())
+    };
+    let text = |key: &str| param(key).map(|value| String::from_utf8_lossy(value).into_owned());
+    let number = |key: &str| -> Result<Option<u64>, String> {
+        text(key)
+            .map(|value| value.parse().map_err(|_| format!("invalid {}", key)))
+            .transpose()
+    };
+
+    let info_hash = param("info_hash")
+        .and_then(|value| value.try_into().ok())
+        .ok_or("invalid info_hash")?;
+    let peer_id = param("peer_id")
+        .and_then(|value| value.try_into().ok())
+        .map(PeerId)
+        .ok_or("invalid peer_id")?;
+    let port = number("port")?
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or("invalid port")?;
+    let event = match text("event").as_deref() {
+        None | Some("") | Some("empty") => None,
+        Some(event) => Some(event.parse().map_err(|_| "invalid event")?),
+    };
+    let ipv6 = text("ipv6")
+        .map(|ip| ip.parse().map_err(|_| "invalid ipv6"))
+        .transpose()?;
+
+    let request = TrackerRequest {
+        peer_id,
+        port,
+        uploaded: number("uploaded")?.unwrap_or(0),
+        downloaded: number("downloaded")?.unwrap_or(0),
+        // Without it a leecher would be taken for a seeder.
+        left: number("left")?.ok_or("missing left")?,
+        compact: match text("compact").as_deref() {
+            None | Some("0") => 0,
+            Some("1") => 1,
+            Some(_) => return Err("invalid compact".to_string()),
+        },
+        event,
+        ipv6,
+    };
+    Ok(AnnounceRequest {
+        info_hash,
+        request,
+        num_want: number("numwant")?.map(|n| n as usize),
+        remote,
+    })
+}
+
+/// How often the secret behind UDP connection ids changes. Ids minted under
+/// the current or the previous secret are accepted, so each stays valid for
+/// at least this long, well past the minute BEP 15 lets clients use it.
+const SECRET_ROTATION: Duration = Duration::from_secs(120);
+
+/// Mints UDP connection ids as a keyed hash of the client's IP, so nothing
+/// has to be stored per client. The port is left out because clients may
+/// reuse an id from a fresh socket.
+struct ConnectionSecrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+impl ConnectionSecrets {
+    fn new() -> Self {
+        Self {
+            current: rand::random(),
+            previous: rand::random(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= SECRET_ROTATION {
+            self.rotate();
+        }
+    }
+
+    fn rotate(&mut self) {
+        self.previous = self.current;
+        self.current = rand::random();
+        self.rotated = Instant::now();
+    }
+
+    fn connection_id(secret: &[u8; 16], remote: SocketAddr) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(secret);
+        hasher.update(remote.ip().to_string());
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    fn issue(&self, remote: SocketAddr) -> u64 {
+        Self::connection_id(&self.current, remote)
+    }
+
+    fn is_valid(&self, connection_id: u64, remote: SocketAddr) -> bool {
+        [&self.current, &self.previous]
+            .into_iter()
+            .any(|secret| Self::connection_id(secret, remote) == connection_id)
+    }
+}
+
+/// Serves BEP 15 connect, announce and scrape until the socket fails.
+pub async fn serve_udp(socket: UdpSocket, swarms: Arc<Swarms>) -> Result<()> {
+    let mut secrets = ConnectionSecrets::new();
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (len, remote) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) if is_transient(&e) => {
+                eprintln!("UDP receive failed: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        secrets.rotate_if_due();
+        if let Some(reply) = handle_udp(&buf[..len], remote, &secrets, &swarms) {
+            // One client's unreachable address is no reason to stop serving.
+            if let Err(e) = socket.send_to(&reply, remote).await {
+                eprintln!("{} -> {}", remote, e);
+            }
+        }
+    }
+}
+
+/// Errors about a single datagram or remote, typically an ICMP error for an
+/// earlier reply, after which the socket still works.
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Answers one datagram; malformed ones are dropped without a reply.
+fn handle_udp(
+    packet: &[u8],
+    remote: SocketAddr,
+    secrets: &ConnectionSecrets,
+    swarms: &Swarms,
+) -> Option<Vec<u8>> {
+    let connection_id = u64::from_be_bytes(packet.get(0..8)?.try_into().ok()?);
+    let action = u32::from_be_bytes(packet.get(8..12)?.try_into().ok()?);
+    let transaction_id = packet.get(12..16)?;
+    let body = &packet[16..];
+
+    let mut reply = action.to_be_bytes().to_vec();
+    reply.extend_from_slice(transaction_id);
+    let error = |message: &str| {
+        let mut error = ACTION_ERROR.to_be_bytes().to_vec();
+        error.extend_from_slice(transaction_id);
+        error.extend_from_slice(message.as_bytes());
+        Some(error)
+    };
+
+    if action == ACTION_CONNECT {
+        if connection_id != PROTOCOL_ID {
+            return None;
+        }
+        reply.extend_from_slice(&secrets.issue(remote).to_be_bytes());
+        return Some(reply);
+    }
+    if !secrets.is_valid(connection_id, remote) {
+        return error("invalid connection id");
+    }
+
+    match action {
+        ACTION_ANNOUNCE => {
+            let Some(announce) = parse_udp_announce(body, remote) else {
+                return error("malformed announce");
+            };
+            let response = match swarms.announce(&announce) {
+                Ok(response) => response,
+                Err(reason) => return error(&reason),
+            };
+            reply.extend_from_slice(&response.interval.unwrap_or_default().to_be_bytes());
+            reply.extend_from_slice(&response.incomplete.unwrap_or_default().to_be_bytes());
+            reply.extend_from_slice(&response.complete.unwrap_or_default().to_be_bytes());
+            // Only peers of the family the client asked over fit the reply.
+            match (&response.peers, remote) {
+                (Peers::Compact(peers), SocketAddr::V4(_)) => reply.extend_from_slice(peers),
+                (_, SocketAddr::V6(_)) => reply.extend_from_slice(&response.peers6),
+                _ => {}
+            }
+        }
+        ACTION_SCRAPE => {
+            let info_hashes = body
+                .chunks_exact(20)
+                .take(MAX_SCRAPE_HASHES)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect::<Vec<[u8; 20]>>();
+            let known = swarms.scrape(&info_hashes);
+            // Every hash gets an entry, in request order; unknown ones are zero.
+            for info_hash in &info_hashes {
+                let stats = known
+                    .iter()
+                    .find(|(known_hash, _)| known_hash == info_hash)
+                    .map(|(_, stats)| *stats);
+                let (seeders, completed, leechers) = stats
+                    .map(|stats| (stats.seeders, stats.completed, stats.leechers))
+                    .unwrap_or_default();
+                for value in [seeders, completed, leechers] {
+                    reply.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+        }
+        _ => return error("unknown action"),
+    }
+    Some(reply)
+}
+
+fn parse_udp_announce(body: &[u8], remote: SocketAddr) -> Option<AnnounceRequest> {
+    if body.len() < 82 {
+        return None;
+    }
+    let u32_at = |at: usize| u32::from_be_bytes(body[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+    let request = TrackerRequest {
+        peer_id: PeerId(body[20..40].try_into().unwrap()),
+        downloaded: u64_at(40),
+        left: u64_at(48),
+        uploaded: u64_at(56),
+        event: Event::from_udp_code(u32_at(64))?,
+        port: u16::from_be_bytes([body[80], body[81]]),
+        compact: 1,
+        ipv6: None,
+    };
+    // -1 asks for the tracker's default.
+    let num_want = match u32_at(76) as i32 {
+        n if n < 0 => None,
+        n => Some(n as usize),
+    };
+    Some(AnnounceRequest {
+        info_hash: body[0..20].try_into().unwrap(),
+        request,
+        num_want,
+        remote,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::{
+        testing::{spawn_tcp, spawn_udp},
+        torrent::{Info, Torrent},
+        tracker::{self, TrackerError},
+        udp_tracker::UdpTracker,
+    };
+
+    async fn start(config: ServerConfig) -> String {
+        let swarms = Arc::new(Swarms::new(config));
+        let address = spawn_tcp("127.0.0.1:0", |listener| serve_http(listener, swarms)).await;
+        format!("http://{address}/announce")
+    }
+
+    fn request(id: u8, port: u16, left: u64) -> TrackerRequest {
+        let mut request = TrackerRequest::new(left);
+        request.peer_id = PeerId([id; 20]);
+        request.port = port;
+        request.ipv6 = None;
+        request
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_scrape_over_http() {
+        let url = start(ServerConfig::new(Duration::from_secs(60))).await;
+        let info_hash = [7; 20];
+
+        let mut seeder = request(b's', 7001, 0);
+        seeder.event = Some(Event::Started);
+        let response = tracker::announce(&url, &info_hash, &seeder, None)
+            .await
+            .unwrap();
+        assert!(response.peers().is_empty());
+
+        let mut leecher = request(b'l', 7002, 100);
+        leecher.compact = 0;
+        let response = tracker::announce(&url, &info_hash, &leecher, None)
+            .await
+            .unwrap();
+        assert_eq!(response.interval, Some(60));
+        assert_eq!((response.complete, response.incomplete), (Some(1), Some(1)));
+        let Peers::Dicts(entries) = &response.peers else {
+            panic!("expected dict peers, got {:?}", response.peers);
+        };
+        assert_eq!(entries[0].peer_id.as_deref(), Some(&vec![b's'; 20]));
+        assert_eq!(response.peers(), ["127.0.0.1:7001".parse().unwrap()]);
+
+        leecher.compact = 1;
+        leecher.event = Some(Event::Completed);
+        leecher.left = 0;
+        tracker::announce(&url, &info_hash, &leecher, None)
+            .await
+            .unwrap();
+        let stats = tracker::scrape(&url, &[info_hash, [8; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                info_hash,
+                ScrapeStats {
+                    seeders: 2,
+                    completed: 1,
+                    leechers: 0
+                }
+            )])
+        );
+
+        seeder.event = Some(Event::Stopped);
+        tracker::announce(&url, &info_hash, &seeder, None)
+            .await
+            .unwrap();
+        let response = tracker::announce(&url, &info_hash, &leecher, None)
+            .await
+            .unwrap();
+        assert!(response.peers().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allow_list() {
+        let mut config = ServerConfig::new(Duration::from_secs(60));
+        config.allow_list = Some(HashSet::from([[1; 20]]));
+        let url = start(config).await;
+
+        let error = tracker::announce(&url, &[2; 20], &request(1, 7001, 0), None)
+            .await
+            .unwrap_err();
+        let error = error.downcast::<TrackerError>().unwrap();
+        assert!(matches!(error, TrackerError::Failure(reason) if reason == "unregistered torrent"));
+        assert!(
+            tracker::announce(&url, &[1; 20], &request(1, 7001, 0), None)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_peers_expire() {
+        let mut config = ServerConfig::new(Duration::from_secs(60));
+        config.peer_timeout = Duration::ZERO;
+        let swarms = Swarms::new(config);
+        let remote = "10.0.0.1:50000".parse().unwrap();
+        for id in [1, 2] {
+            let announce = AnnounceRequest {
+                info_hash: [3; 20],
+                request: request(id, 7000, 10),
+                num_want: None,
+                remote,
+            };
+            let response = swarms.announce(&announce).unwrap();
+            assert!(response.peers().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_completions_count_once_and_empty_swarms_go() {
+        let swarms = Swarms::new(ServerConfig::new(Duration::from_secs(60)));
+        let announce = |info_hash, id, left, event| {
+            let mut request = request(id, 7000, left);
+            request.event = event;
+            let announce = AnnounceRequest {
+                info_hash,
+                request,
+                num_want: None,
+                remote: "10.0.0.1:50000".parse().unwrap(),
+            };
+            swarms.announce(&announce).unwrap();
+        };
+        let completed = || swarms.scrape(&[[1; 20]]).first().map(|(_, s)| s.completed);
+
+        announce([1; 20], 1, 0, Some(Event::Started));
+        assert_eq!(completed(), Some(0));
+        announce([1; 20], 2, 100, Some(Event::Started));
+        announce([1; 20], 2, 0, None);
+        announce([1; 20], 2, 0, Some(Event::Completed));
+        assert_eq!(completed(), Some(1));
+        announce([1; 20], 3, 0, Some(Event::Completed));
+        announce([1; 20], 3, 0, Some(Event::Completed));
+        assert_eq!(completed(), Some(2));
+
+        announce([2; 20], 1, 0, Some(Event::Stopped));
+        for id in [1, 2, 3] {
+            announce([1; 20], id, 0, Some(Event::Stopped));
+        }
+        assert!(swarms.scrape(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_sweep_frees_idle_swarms() {
+        let mut config = ServerConfig::new(Duration::from_secs(60));
+        config.peer_timeout = Duration::ZERO;
+        let swarms = Swarms::new(config);
+        for info_hash in [[1; 20], [2; 20]] {
+            let announce = AnnounceRequest {
+                info_hash,
+                request: request(1, 7000, 10),
+                num_want: None,
+                remote: "10.0.0.1:50000".parse().unwrap(),
+            };
+            swarms.announce(&announce).unwrap();
+        }
+        assert!(swarms.scrape(&[[1; 20]]).is_empty());
+        assert_eq!(swarms.swarms.lock().unwrap().len(), 1);
+        swarms.sweep();
+        assert!(swarms.swarms.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_announce() {
+        let remote = "10.0.0.1:50000".parse().unwrap();
+        let parse = |query: &str| {
+            let query = format!(
+                "info_hash=aaaaaaaaaaaaaaaaaaaa&peer_id=-CR0100-aaaaaaaaaaaa&port=6881{query}"
+            );
+            parse_announce(&parse_query(&query), remote)
+        };
+        let announce = parse("&left=10&compact=1").unwrap();
+        assert_eq!((announce.request.left, announce.request.compact), (10, 1));
+        assert_eq!(parse("&left=0").unwrap().request.compact, 0);
+        assert_eq!(parse("&compact=1").err().as_deref(), Some("missing left"));
+        assert_eq!(
+            parse("&left=0&compact=257").err().as_deref(),
+            Some("invalid compact")
+        );
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("info_hash=%D6%9F+x&peer_id=-CR0100-&port=6881&flag");
+        assert_eq!(
+            params[0],
+            ("info_hash".to_string(), vec![0xd6, 0x9f, b' ', b'x'])
+        );
+        assert_eq!(params[1].1, b"-CR0100-");
+        assert_eq!(params[3], ("flag".to_string(), vec![]));
+        assert_eq!(percent_decode("100%"), b"100%");
+    }
+
+    #[tokio::test]
+    async fn test_udp_tracker_end_to_end() {
+        let swarms = Arc::new(Swarms::new(ServerConfig::new(Duration::from_secs(60))));
+        let address = spawn_udp("127.0.0.1:0", |socket| serve_udp(socket, swarms)).await;
+        let url = format!("udp://{address}/announce");
+
+        // Another client joins the swarm first...
+        let info = Info::single_file("data".to_string(), 10, 16 * 1024);
+        let torrent = Torrent::from_info(url.clone(), info).unwrap();
+        let other = UdpTracker::new(&url).await.unwrap();
+        let response = other
+            .announce(&torrent.info_hash(), &request(b'o', 7003, 0))
+            .await
+            .unwrap();
+        assert_eq!(response.complete, Some(1));
+
+        // ...and is what the torrent finds through its UDP announce.
+        let peers = torrent.get_peer_addrs().await.unwrap();
+        assert_eq!(peers, ["127.0.0.1:7003".parse().unwrap()]);
+
+        let stats = tracker::scrape(&url, &[torrent.info_hash(), [0; 20]])
+            .await
+            .unwrap();
+        assert_eq!(stats[&torrent.info_hash()].seeders, 1);
+        assert_eq!(stats[&torrent.info_hash()].leechers, 1);
+        assert_eq!(stats[&[0; 20]].seeders, 0);
+    }
+
+    #[test]
+    fn test_udp_connection_ids() {
+        let swarms = Swarms::new(ServerConfig::new(Duration::from_secs(60)));
+        let mut secrets = ConnectionSecrets::new();
+        let remote: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let packet = |connection_id: u64, action: u32| {
+            let mut packet = connection_id.to_be_bytes().to_vec();
+            packet.extend_from_slice(&action.to_be_bytes());
+            packet.extend_from_slice(&[9, 9, 9, 9]);
+            packet
+        };
+        let action = |reply: &[u8]| u32::from_be_bytes(reply[..4].try_into().unwrap());
+
+        let reply = handle_udp(
+            &packet(PROTOCOL_ID, ACTION_CONNECT),
+            remote,
+            &secrets,
+            &swarms,
+        )
+        .unwrap();
+        assert_eq!(reply[4..8], [9, 9, 9, 9]);
+        let connection_id = u64::from_be_bytes(reply[8..16].try_into().unwrap());
+
+        let scrape = packet(connection_id, ACTION_SCRAPE);
+        let reply = handle_udp(&scrape, remote, &secrets, &swarms).unwrap();
+        assert_eq!(action(&reply), ACTION_SCRAPE);
+        // Bound to the address it was issued to.
+        let elsewhere = "10.0.0.2:4001".parse().unwrap();
+        let reply = handle_udp(&scrape, elsewhere, &secrets, &swarms).unwrap();
+        assert_eq!(action(&reply), ACTION_ERROR);
+
+        // Still good after one rotation, gone after two.
+        secrets.rotate();
+        let reply = handle_udp(&scrape, remote, &secrets, &swarms).unwrap();
+        assert_eq!(action(&reply), ACTION_SCRAPE);
+        secrets.rotate();
+        let reply = handle_udp(&scrape, remote, &secrets, &swarms).unwrap();
+        assert_eq!(reply[8..], *b"invalid connection id");
+    }
+}
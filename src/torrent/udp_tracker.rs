@@ -0,0 +1,419 @@
+use crate::torrent::tracker::{
+    Event, Peers, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse,
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    time::timeout_at,
+};
+use url::Url;
+
+pub const PROTOCOL_ID: u64 = 0x41727101980;
+pub const ACTION_CONNECT: u32 = 0;
+pub const ACTION_ANNOUNCE: u32 = 1;
+pub const ACTION_SCRAPE: u32 = 2;
+pub const ACTION_ERROR: u32 = 3;
+
+/// BEP 15: a connection id may be used for one minute after it was issued.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// BEP 15: wait 15 * 2^n seconds for the n-th attempt, giving up after n = 8.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const MAX_RETRIES: u32 = 8;
+/// The most info hashes that fit in one scrape request.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Connection ids by tracker address, so consecutive requests to the same
+/// tracker skip the connect round trip.
+static CONNECTIONS: LazyLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> =
+    LazyLock::new(Default::default);
+
+/// Lets trackers recognise us if our address changes, so it has to stay the
+/// same for the whole session.
+static KEY: LazyLock<u32> = LazyLock::new(|| rand::thread_rng().gen());
+
+pub struct UdpTracker {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    base_timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves a `udp://host:port[/path]` tracker URL.
+    pub async fn new(url: &str) -> Result<Self> {
+        let url = Url::parse(url)?;
+        ensure!(url.scheme() == "udp", "not a UDP tracker: {}", url);
+        let host = url.host_str().ok_or(anyhow!("tracker URL has no host"))?;
+        let port = url.port().ok_or(anyhow!("tracker URL has no port"))?;
+        // IPv6 literals come back from `host_str` in brackets.
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addr = lookup_host((host, port))
+            .await?
+            .next()
+            .with_context(|| format!("could not resolve {}", host))?;
+        Self::with_addr(addr).await
+    }
+
+    /// Gives up after `max_retries` retransmissions instead of BEP 15's
+    /// eight, for callers with other trackers to fall back on.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub async fn with_addr(addr: SocketAddr) -> Result<Self> {
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(addr).await?;
+        Ok(Self {
+            socket,
+            addr,
+            base_timeout: BASE_TIMEOUT,
+            max_retries: MAX_RETRIES,
+        })
+    }
+
+    pub async fn announce(
+        &self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(info_hash);
+        body.extend_from_slice(&request.peer_id.0);
+        body.extend_from_slice(&request.downloaded.to_be_bytes());
+        body.extend_from_slice(&request.left.to_be_bytes());
+        body.extend_from_slice(&request.uploaded.to_be_bytes());
+        body.extend_from_slice(&Event::udp_code(request.event).to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes()); // ip: the sender's
+        body.extend_from_slice(&KEY.to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+        body.extend_from_slice(&request.port.to_be_bytes());
+
+        let response = self.request(ACTION_ANNOUNCE, &body).await?;
+        ensure!(response.len() >= 12, "announce response too short");
+        let interval = u32::from_be_bytes(response[0..4].try_into()?);
+        let leechers = u32::from_be_bytes(response[4..8].try_into()?);
+        let seeders = u32::from_be_bytes(response[8..12].try_into()?);
+        // Peers come in the address family we reached the tracker over.
+        let peers = response[12..].to_vec();
+        let (peers, peers6) = match self.addr {
+            SocketAddr::V4(_) => (peers, vec![]),
+            SocketAddr::V6(_) => (vec![], peers),
+        };
+        Ok(TrackerResponse {
+            interval: Some(interval),
+            complete: Some(seeders),
+            incomplete: Some(leechers),
+            peers: Peers::Compact(peers),
+            peers6,
+            ..Default::default()
+        })
+    }
+
+    /// Fetches swarm counts for up to [`MAX_SCRAPE_HASHES`] info hashes, in
+    /// the order given.
+    pub async fn scrape(&self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>> {
+        ensure!(
+            info_hashes.len() <= MAX_SCRAPE_HASHES,
+            "at most {} info hashes per scrape",
+            MAX_SCRAPE_HASHES
+        );
+        let response = self.request(ACTION_SCRAPE, &info_hashes.concat()).await?;
+        ensure!(
+            response.len() >= info_hashes.len() * 12,
+            "scrape response too short"
+        );
+        let stats = response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|chunk| ScrapeStats {
+                seeders: u32::from_be_bytes(chunk[0..4].try_into().unwrap()),
+                completed: u32::from_be_bytes(chunk[4..8].try_into().unwrap()),
+                leechers: u32::from_be_bytes(chunk[8..12].try_into().unwrap()),
+            })
+            .collect();
+        Ok(stats)
+    }
+
+    /// Sends `action` with `body`, connecting first when there is no fresh
+    /// connection id, and retransmits on the BEP 15 schedule.
+    async fn request(&self, action: u32, body: &[u8]) -> Result<Vec<u8>> {
+        // A tracker that restarted, or expires ids early, rejects a cached
+        // connection id with an error; that earns one fresh connect.
+        let mut reconnected = false;
+        'attempts: for n in 0..=self.max_retries {
+            let deadline = Instant::now() + self.base_timeout * 2u32.pow(n);
+            loop {
+                let (connection_id, cached) = match self.cached_connection_id() {
+                    Some(connection_id) => (connection_id, true),
+                    None => match self.connect(deadline).await? {
+                        Some(connection_id) => (connection_id, false),
+                        None => continue 'attempts,
+                    },
+                };
+                match self.exchange(connection_id, action, body, deadline).await {
+                    Ok(Some(response)) => return Ok(response),
+                    Ok(None) => continue 'attempts,
+                    Err(e) if cached && !reconnected && e.is::<TrackerError>() => {
+                        CONNECTIONS.lock().unwrap().remove(&self.addr);
+                        reconnected = true;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        bail!("tracker {} did not respond", self.addr)
+    }
+
+    /// Obtains and caches a new connection id, or `None` on timeout.
+    async fn connect(&self, deadline: Instant) -> Result<Option<u64>> {
+        let Some(response) = self
+            .exchange(PROTOCOL_ID, ACTION_CONNECT, &[], deadline)
+            .await?
+        else {
+            return Ok(None);
+        };
+        ensure!(response.len() >= 8, "connect response too short");
+        let connection_id = u64::from_be_bytes(response[0..8].try_into()?);
+        CONNECTIONS
+            .lock()
+            .unwrap()
+            .insert(self.addr, (connection_id, Instant::now()));
+        Ok(Some(connection_id))
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let mut connections = CONNECTIONS.lock().unwrap();
+        match connections.get(&self.addr) {
+            Some(&(connection_id, issued)) if issued.elapsed() < CONNECTION_ID_LIFETIME => {
+                Some(connection_id)
+            }
+            _ => {
+                connections.remove(&self.addr);
+                None
+            }
+        }
+    }
+
+    /// Sends one packet and waits until `deadline` for the reply carrying its
+    /// transaction id, returning the payload after the 8-byte header.
+    async fn exchange(
+        &self,
+        connection_id: u64,
+        action: u32,
+        body: &[u8],
+        deadline: Instant,
+    ) -> Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::thread_rng().gen();
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        self.socket.send(&packet).await?;
+
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let Ok(len) = timeout_at(deadline.into(), self.socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let response = &buf[..len?];
+            if response.len() < 8 || response[4..8] != transaction_id.to_be_bytes() {
+                // Late replies to earlier attempts, or noise.
+                continue;
+            }
+            let response_action = u32::from_be_bytes(response[0..4].try_into()?);
+            if response_action == ACTION_ERROR {
+                let reason = String::from_utf8_lossy(&response[8..]).into_owned();
+                return Err(TrackerError::Failure(reason).into());
+            }
+            ensure!(
+                response_action == action,
+                "expected action {}, tracker replied with {}",
+                action,
+                response_action
+            );
+            return Ok(Some(response[8..].to_vec()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::torrent::testing::spawn_udp;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const CONNECTION_ID: u64 = 0x1122334455667788;
+
+    /// Answers connects and announces like a tracker, but ignores the first
+    /// `drop_announces` announces and prefixes each reply with a packet
+    /// carrying the wrong transaction id. Returns its address and a counter
+    /// of connect requests.
+    async fn stand_in(bind: &str, drop_announces: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let counter = connects.clone();
+        let addr = spawn_udp(bind, |socket| async move {
+            let mut buf = [0u8; 2048];
+            let mut announces = 0;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let packet = &buf[..len];
+                let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+                let transaction_id = &packet[12..16];
+                let mut reply = action.to_be_bytes().to_vec();
+                reply.extend_from_slice(transaction_id);
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(packet[..8], PROTOCOL_ID.to_be_bytes());
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        reply.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                    }
+                    _ if packet[..8] != CONNECTION_ID.to_be_bytes() => {
+                        let mut error = ACTION_ERROR.to_be_bytes().to_vec();
+                        error.extend_from_slice(transaction_id);
+                        error.extend_from_slice(b"connection id mismatch");
+                        socket.send_to(&error, from).await.unwrap();
+                        continue;
+                    }
+                    ACTION_ANNOUNCE => {
+                        assert_eq!(packet.len(), 98);
+                        assert_eq!(packet[88..92], KEY.to_be_bytes());
+                        announces += 1;
+                        if announces <= drop_announces {
+                            continue;
+                        }
+                        reply.extend_from_slice(&[0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2]);
+                        if from.is_ipv4() {
+                            reply.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                        } else {
+                            reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                            reply.extend_from_slice(&[0x1a, 0xe1]);
+                        }
+                    }
+                    ACTION_SCRAPE => {
+                        for hash in packet[16..].chunks_exact(20) {
+                            let n = hash[0] as u32;
+                            for value in [n, n + 1, n + 2] {
+                                reply.extend_from_slice(&value.to_be_bytes());
+                            }
+                        }
+                    }
+                    _ => {
+                        let mut error = ACTION_ERROR.to_be_bytes().to_vec();
+                        error.extend_from_slice(transaction_id);
+                        error.extend_from_slice(b"unknown action");
+                        socket.send_to(&error, from).await.unwrap();
+                        continue;
+                    }
+                }
+                let mut stale = reply.clone();
+                stale[4] ^= 0xff;
+                socket.send_to(&stale, from).await.unwrap();
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        })
+        .await;
+        (addr, connects)
+    }
+
+    async fn client(addr: SocketAddr) -> UdpTracker {
+        let mut tracker = UdpTracker::with_addr(addr).await.unwrap();
+        tracker.base_timeout = Duration::from_millis(50);
+        tracker.max_retries = 3;
+        tracker
+    }
+
+    #[tokio::test]
+    async fn test_announce_retransmits_and_caches_connection() {
+        let (addr, connects) = stand_in("127.0.0.1:0", 2).await;
+        let tracker = client(addr).await;
+        let request = TrackerRequest::new(1000);
+
+        let response = tracker.announce(&[7; 20], &request).await.unwrap();
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!((response.complete, response.incomplete), (Some(2), Some(1)));
+        assert_eq!(response.peers(), ["10.0.0.1:6881".parse().unwrap()]);
+
+        tracker.announce(&[7; 20], &request).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_reconnects_once_on_rejected_connection_id() {
+        let (addr, connects) = stand_in("127.0.0.1:0", 0).await;
+        CONNECTIONS
+            .lock()
+            .unwrap()
+            .insert(addr, (!CONNECTION_ID, Instant::now()));
+        let tracker = client(addr).await;
+
+        let response = tracker.announce(&[7; 20], &TrackerRequest::new(0)).await;
+        assert_eq!(response.unwrap().interval, Some(1800));
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_over_ipv6() {
+        let (addr, _) = stand_in("[::1]:0", 0).await;
+        let tracker = UdpTracker::new(&format!("udp://{}/announce", addr))
+            .await
+            .unwrap();
+        let response = tracker
+            .announce(&[1; 20], &TrackerRequest::new(0))
+            .await
+            .unwrap();
+        assert_eq!(response.peers(), ["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let (addr, _) = stand_in("127.0.0.1:0", 0).await;
+        let stats = client(addr)
+            .await
+            .scrape(&[[3; 20], [9; 20]])
+            .await
+            .unwrap();
+        assert_eq!(
+            stats,
+            [
+                ScrapeStats {
+                    seeders: 3,
+                    completed: 4,
+                    leechers: 5
+                },
+                ScrapeStats {
+                    seeders: 9,
+                    completed: 10,
+                    leechers: 11
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_and_silence() {
+        let (addr, _) = stand_in("127.0.0.1:0", 0).await;
+        let error = client(addr).await.request(9, &[]).await.unwrap_err();
+        assert_eq!(error.to_string(), "tracker error: unknown action");
+
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut tracker = client(silent.local_addr().unwrap()).await;
+        tracker.max_retries = 1;
+        assert!(tracker.scrape(&[[0; 20]]).await.is_err());
+    }
+}